@@ -0,0 +1,153 @@
+#[cfg(feature = "libpcap")]
+use std::ffi::{CStr, CString};
+#[cfg(feature = "libpcap")]
+use std::ptr;
+
+use libc::c_uint;
+#[cfg(feature = "libpcap")]
+use libc::{c_char, c_int, timeval};
+
+
+#[cfg(feature = "libpcap")]
+const DLT_EN10MB: c_int = 1;
+#[cfg(feature = "libpcap")]
+const SNAPLEN: c_int = 65535;
+#[cfg(feature = "libpcap")]
+const PCAP_NETMASK_UNKNOWN: u32 = 0xffffffff;
+
+
+/// Single BPF instruction, laid out like the kernel `sock_filter`.
+#[repr(C)]
+#[derive(Debug,Clone,Copy)]
+pub struct BpfInstruction {
+    pub code: u16,
+    pub jt: u8,
+    pub jf: u8,
+    pub k: u32,
+}
+
+
+#[cfg_attr(not(feature = "libpcap"), allow(dead_code))]
+#[repr(C)]
+struct RawProgram {
+    bf_len: c_uint,
+    bf_insns: *mut BpfInstruction,
+}
+
+
+#[cfg(feature = "libpcap")]
+#[repr(C)]
+struct PcapHeader {
+    ts: timeval,
+    caplen: u32,
+    len: u32,
+}
+
+
+#[cfg(feature = "libpcap")]
+enum Pcap {}
+
+
+#[cfg(feature = "libpcap")]
+#[link(name = "pcap")]
+extern "C" {
+    fn pcap_open_dead(linktype: c_int, snaplen: c_int) -> *mut Pcap;
+    fn pcap_close(p: *mut Pcap);
+    fn pcap_compile(p: *mut Pcap, program: *mut RawProgram, expression: *const c_char, optimize: c_int, netmask: u32) -> c_int;
+    fn pcap_geterr(p: *mut Pcap) -> *mut c_char;
+    fn pcap_freecode(program: *mut RawProgram);
+    fn pcap_offline_filter(program: *const RawProgram, header: *const PcapHeader, packet: *const u8) -> c_int;
+}
+
+
+/// Filter compiled by libpcap from a tcpdump expression for Ethernet frames.
+/// Without the `libpcap` feature the probe does not link libpcap and filters
+/// are rejected.
+#[cfg_attr(not(feature = "libpcap"), allow(dead_code))]
+pub struct BpfProgram {
+    expression: String,
+    program: RawProgram,
+}
+
+// The instructions are owned by the program and never shared.
+unsafe impl Send for BpfProgram {}
+
+impl BpfProgram {
+    #[cfg(not(feature = "libpcap"))]
+    pub fn compile(expression: &str) -> Result<BpfProgram, String> {
+        Err(format!("Unable to compile filter `{}`, the probe was built without the `libpcap` feature", expression))
+    }
+
+    #[cfg(feature = "libpcap")]
+    pub fn compile(expression: &str) -> Result<BpfProgram, String> {
+        let c_expression = match CString::new(expression) {
+            Ok(c_expression) => c_expression,
+            Err(e) => return Err(format!("Invalid filter `{}`: {}", expression, e))
+        };
+        let mut program = RawProgram {
+            bf_len: 0,
+            bf_insns: ptr::null_mut()
+        };
+        unsafe {
+            let pcap = pcap_open_dead(DLT_EN10MB, SNAPLEN);
+            if pcap.is_null() {
+                return Err("Unable to open libpcap for filter compilation".to_string());
+            }
+            let result = pcap_compile(pcap, &mut program, c_expression.as_ptr(), 1, PCAP_NETMASK_UNKNOWN);
+            let error = if result < 0 {
+                Some(CStr::from_ptr(pcap_geterr(pcap)).to_string_lossy().into_owned())
+            } else {
+                None
+            };
+            pcap_close(pcap);
+            if let Some(error) = error {
+                return Err(format!("Invalid filter `{}`: {}", expression, error));
+            }
+        }
+        Ok(BpfProgram {
+            expression: expression.to_string(),
+            program: program
+        })
+    }
+
+    pub fn expression(&self) -> &str {
+        &self.expression
+    }
+
+    pub fn instructions(&self) -> &[BpfInstruction] {
+        unsafe {
+            ::std::slice::from_raw_parts(self.program.bf_insns, self.program.bf_len as usize)
+        }
+    }
+
+    /// Runs the filter in userspace, used for frames read from capture files.
+    #[cfg(not(feature = "libpcap"))]
+    pub fn matches(&self, _frame: &[u8]) -> bool {
+        unreachable!()
+    }
+
+    /// Runs the filter in userspace, used for frames read from capture files.
+    #[cfg(feature = "libpcap")]
+    pub fn matches(&self, frame: &[u8]) -> bool {
+        let header = PcapHeader {
+            ts: timeval {
+                tv_sec: 0,
+                tv_usec: 0
+            },
+            caplen: frame.len() as u32,
+            len: frame.len() as u32
+        };
+        unsafe {
+            pcap_offline_filter(&self.program, &header, frame.as_ptr()) != 0
+        }
+    }
+}
+
+#[cfg(feature = "libpcap")]
+impl Drop for BpfProgram {
+    fn drop(&mut self) {
+        unsafe {
+            pcap_freecode(&mut self.program);
+        }
+    }
+}
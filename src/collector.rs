@@ -1,25 +1,112 @@
 use std::mem::swap;
 use std::collections::HashMap;
-// use std::collections::hash_map::Iter;
+use std::collections::hash_map::{Entry, Iter};
 use std::iter::IntoIterator;
-// use std::cmp;
+use std::net::IpAddr;
+use std::cmp;
 use std::sync::mpsc::{Receiver, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 use time;
 
+use flow_key::FlowKey;
+use monitor::{self, KernelStatistics};
+use ttl::{HopChange, HopDistance, HopTracker};
 
-pub type SimpleIpfix = (String, String, &'static str, Vec<(&'static str, String)>);
+
+#[derive(Debug,Clone)]
+pub struct Sample {
+    pub key: FlowKey,
+    pub source: IpAddr,
+    pub ttl: u8,
+    pub length: u32,
+    pub timestamp: u64,
+    /// Part of a fragmented datagram.
+    pub fragment: bool,
+}
+
+impl Sample {
+    pub fn new(key: FlowKey, source: IpAddr, ttl: u8, length: u32, timestamp: u64) -> Sample {
+        Sample {
+            key: key,
+            source: source,
+            ttl: ttl,
+            length: length,
+            timestamp: timestamp,
+            fragment: false
+        }
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct Stats {
+    pub count: u32,
+    pub bytes: u64,
+    pub min_size: u32,
+    pub max_size: u32,
+    pub min_ttl: u8,
+    pub max_ttl: u8,
+    pub last_ttl: u8,
+    /// Packets that were fragments, scaled like `count`.
+    pub fragments: u32,
+}
+
+impl Stats {
+    pub fn new(count: u32, ttl: u8, length: u32) -> Stats {
+        Stats {
+            count: count,
+            bytes: count as u64 * length as u64,
+            min_size: length,
+            max_size: length,
+            min_ttl: ttl,
+            max_ttl: ttl,
+            last_ttl: ttl,
+            fragments: 0u32
+        }
+    }
+
+    /// `count` packets of `length` bytes, counts are scaled by the sampling rate.
+    pub fn update(&mut self, count: u32, ttl: u8, length: u32) -> () {
+        self.count += count;
+        self.bytes += count as u64 * length as u64;
+        self.min_size = cmp::min(self.min_size, length);
+        self.max_size = cmp::max(self.max_size, length);
+        self.min_ttl = cmp::min(self.min_ttl, ttl);
+        self.max_ttl = cmp::max(self.max_ttl, ttl);
+        self.last_ttl = ttl;
+    }
+
+    pub fn merge(&mut self, stats: &Stats) -> () {
+        self.count += stats.count;
+        self.bytes += stats.bytes;
+        self.min_size = cmp::min(self.min_size, stats.min_size);
+        self.max_size = cmp::max(self.max_size, stats.max_size);
+        self.min_ttl = cmp::min(self.min_ttl, stats.min_ttl);
+        self.max_ttl = cmp::max(self.max_ttl, stats.max_ttl);
+        self.last_ttl = stats.last_ttl;
+        self.fragments += stats.fragments;
+    }
+
+    pub fn mean_size(&self) -> u32 {
+        if self.count == 0 {
+            0u32
+        } else {
+            (self.bytes / self.count as u64) as u32
+        }
+    }
+}
 
 #[derive(Debug,Clone)]
 pub struct MutWindow {
-    samples: HashMap<SimpleIpfix, u32>,
+    samples: HashMap<FlowKey, Stats>,
+    sources: HashMap<IpAddr, u8>,
     sampling: u32,
+    time_from: u64,
 }
 
 impl MutWindow {
-    pub fn new(sampling: u32) -> MutWindow {
+    pub fn new(sampling: u32, time_from: u64) -> MutWindow {
         let smpl = if sampling < 2u32 {
             1u32
         } else {
@@ -27,22 +114,36 @@ impl MutWindow {
         };
         MutWindow {
             samples: HashMap::new(),
-            sampling: smpl
+            sources: HashMap::new(),
+            sampling: smpl,
+            time_from: time_from
         }
     }
 
-    pub fn add(&mut self, signature: SimpleIpfix) -> () {
-        let new_count = match self.samples.get(&signature) {
-            Some(count) => count + self.sampling,
-            None => self.sampling
-        };
-        self.samples.insert(signature, new_count);
+    pub fn add(&mut self, sample: Sample) -> () {
+        let sampling = self.sampling;
+        let Sample { key, source, ttl, length, fragment, .. } = sample;
+        let stats = self.samples.entry(key)
+            .or_insert_with(|| Stats::new(0u32, ttl, length));
+        stats.update(sampling, ttl, length);
+        if fragment {
+            stats.fragments += sampling;
+        }
+        let max_ttl = self.sources.entry(source).or_insert(ttl);
+        *max_ttl = cmp::max(*max_ttl, ttl);
     }
 
-    pub fn end_collecting(self) -> Window {
-        let time_to = time_now();
+    pub fn end_collecting(self, time_to: u64) -> Window {
+        // The highest TTL seen from a source is the one that travelled the fewest hops.
+        let sources = self.sources.into_iter()
+            .map(|(source, ttl)| { (source, HopDistance::from_ttl(ttl)) })
+            .collect();
         Window {
             samples: self.samples,
+            sources: sources,
+            events: vec![],
+            kernel: vec![],
+            time_from: self.time_from,
             time_to: time_to
         }
     }
@@ -54,52 +155,85 @@ impl MutWindow {
 
 #[derive(Debug,Clone)]
 pub struct Window {
-    samples: HashMap<SimpleIpfix, u32>,
+    samples: HashMap<FlowKey, Stats>,
+    sources: HashMap<IpAddr, HopDistance>,
+    events: Vec<HopChange>,
+    kernel: Vec<KernelStatistics>,
+    time_from: u64,
     time_to: u64
 }
 
 impl Window {
 
+    pub fn start_time(&self) -> u64 {
+        self.time_from
+    }
+
     pub fn end_time(&self) -> u64 {
         self.time_to
     }
 
-//    pub fn overlaps(&self, window: &Window) -> bool {
-//        let max_start_time = cmp::max(window.time_from, self.time_from);
-//        let min_end_time = cmp::min(window.time_to, self.time_to);
-//        max_start_time >= min_end_time
-//    }
-    
-//    pub fn union(&self, window: &Window) -> Result<Window, &'static str> {
-//        if self.overlaps(window) {
-//            let min_start_time = cmp::min(self.time_from, window.time_from);
-//            let max_end_time = cmp::max(self.time_to, window.time_to);
-//            let mut new_flows: HashMap<SimpleIpfix, u32> = HashMap::new();
-//            for ipfix in self.samples.keys() {
-//                let self_ipfix_count = match self.samples.get(&ipfix) {
-//                    Some(count) => count.clone(),
-//                    None => 0u32
-//                };
-//                let window_ipfix_count = match window.samples.get(&ipfix) {
-//                    Some(count) => count.clone(),
-//                    None => 0u32
-//                };
-//                new_flows.insert(ipfix.clone(), self_ipfix_count + window_ipfix_count);
-//            }
-//            Ok(Window {samples: new_flows, time_from: min_start_time, time_to: max_end_time})
-//        } else {
-//            Err("Windows does not overlap, there is no reason to make union")
-//        }
-//    }
-//    
-//    pub fn iter(&self) -> Iter<SimpleIpfix, u32> {
-//        self.samples.iter()
-//    }
+    pub fn sources(&self) -> &HashMap<IpAddr, HopDistance> {
+        &self.sources
+    }
+
+    pub fn events(&self) -> &Vec<HopChange> {
+        &self.events
+    }
+
+    pub fn kernel_statistics(&self) -> &Vec<KernelStatistics> {
+        &self.kernel
+    }
+
+    pub fn iter(&self) -> Iter<FlowKey, Stats> {
+        self.samples.iter()
+    }
+
+    /// True when the windows share some time, windows that only touch do not overlap.
+    pub fn overlaps(&self, window: &Window) -> bool {
+        let max_start_time = cmp::max(window.time_from, self.time_from);
+        let min_end_time = cmp::min(window.time_to, self.time_to);
+        max_start_time < min_end_time
+    }
+
+    pub fn adjoins(&self, window: &Window) -> bool {
+        self.time_to == window.time_from || window.time_to == self.time_from
+    }
+
+    /// Adds counters, sources and events of `window` to this one and stretches
+    /// the time range over both. `window` is expected to be the later one.
+    pub fn merge(&mut self, window: Window) -> () {
+        let Window { samples, sources, events, kernel, time_from, time_to } = window;
+        for (ipfix, stats) in samples {
+            match self.samples.entry(ipfix) {
+                Entry::Occupied(mut entry) => entry.get_mut().merge(&stats),
+                Entry::Vacant(entry) => { entry.insert(stats); }
+            }
+        }
+        for (source, distance) in sources {
+            let closest = self.sources.entry(source).or_insert(distance);
+            if distance.hops < closest.hops {
+                *closest = distance;
+            }
+        }
+        self.events.extend(events);
+        for statistics in kernel {
+            match self.kernel.iter_mut().find(|merged| { merged.interface == statistics.interface }) {
+                Some(merged) => {
+                    merged.received += statistics.received;
+                    merged.dropped += statistics.dropped;
+                },
+                None => self.kernel.push(statistics)
+            }
+        }
+        self.time_from = cmp::min(self.time_from, time_from);
+        self.time_to = cmp::max(self.time_to, time_to);
+    }
 }
 
 impl IntoIterator for Window {
-    type Item = (SimpleIpfix, u32);
-    type IntoIter = ::std::collections::hash_map::IntoIter<SimpleIpfix, u32>;
+    type Item = (FlowKey, Stats);
+    type IntoIter = ::std::collections::hash_map::IntoIter<FlowKey, Stats>;
 
     fn into_iter(self) -> Self::IntoIter {
         self.samples.into_iter()
@@ -110,6 +244,7 @@ pub struct WindowCollector {
     window: Option<MutWindow>,
     sampling: u32,
     sender: Sender<Window>,
+    hop_tracker: HopTracker,
 }
 
 impl WindowCollector {
@@ -117,23 +252,46 @@ impl WindowCollector {
         WindowCollector {
             window: None,
             sampling: sampling,
-            sender: sender
+            sender: sender,
+            hop_tracker: HopTracker::new()
         }
     }
 
     pub fn next_window(&mut self) -> () {
-        println!("Call next window");
-        let mut loco_window = Some(MutWindow::new(self.sampling));
+        self.next_window_at(time_now());
+    }
+
+    /// Closes the current window at `time_to` and opens the next one from there,
+    /// offline captures pass packet time here.
+    pub fn next_window_at(&mut self, time_to: u64) -> () {
+        self.next_window_between(time_to, time_to);
+    }
+
+    /// Closes the current window at `time_to` and opens the next one at `time_from`,
+    /// which is later when an offline capture has a gap.
+    pub fn next_window_between(&mut self, time_to: u64, time_from: u64) -> () {
+        let mut loco_window = Some(MutWindow::new(self.sampling, time_from));
         swap(&mut self.window, &mut loco_window);
         if let Some(window) = loco_window {
-            self.sender.send(window.end_collecting()).unwrap();
+            let mut window = window.end_collecting(time_to);
+            window.events = self.hop_tracker.update(window.sources());
+            window.kernel = monitor::read_kernel_statistics();
+            for change in window.events.iter() {
+                warn!("Hop distance of {} changed from {} to {}", change.source, change.previous, change.current);
+            }
+            self.sender.send(window).unwrap();
         };
     }
 
-    pub fn add(&mut self, signature: SimpleIpfix) -> Result<(), &'static str> {
+    /// What the window being collected holds so far, as if it ended at `time_to`.
+    pub fn current_window(&self, time_to: u64) -> Option<Window> {
+        self.window.as_ref().map(|window| { window.clone().end_collecting(time_to) })
+    }
+
+    pub fn add(&mut self, sample: Sample) -> Result<(), &'static str> {
         match self.window {
             Some(ref mut window) => {
-                window.add(signature);
+                window.add(sample);
                 Ok(())
             },
             None => Err("MutWindow was not initialized, run `WindowCollector::next_window` first")
@@ -148,8 +306,10 @@ impl WindowCollector {
 //    }
 }
 
-pub fn run_collector(receiver: Receiver<SimpleIpfix>, sender: Sender<Window>, sampling: u32) {
-    let collector = Arc::new(Mutex::new(WindowCollector::new(sampling, sender)));
+/// Windows are `window_length` milliseconds long and end on multiples of it,
+/// only the first one is shorter as it starts when the collector does.
+/// `collector` is shared so that the window being collected can be looked at.
+pub fn run_collector(receiver: Receiver<Sample>, collector: Arc<Mutex<WindowCollector>>, window_length: u64) -> thread::JoinHandle<()> {
     {
         let mut col = collector.lock().unwrap();
         (*col).next_window();
@@ -158,12 +318,13 @@ pub fn run_collector(receiver: Receiver<SimpleIpfix>, sender: Sender<Window>, sa
     let collector_loop = collector.clone();
     let collector_time = collector.clone();
 
-    thread::spawn(move || {
+    let guard = thread::spawn(move || {
         loop {
             match receiver.recv() {
-                Ok(ipfix) => {
+                Ok(sample) => {
+                    monitor::sample_received();
                     let mut collector_guard = (*collector_loop).lock().unwrap();
-                    (*collector_guard).add(ipfix).unwrap();
+                    (*collector_guard).add(sample).unwrap();
                 },
                 Err(e) => {
                     error!("Collector receiver error occured: {}", e);
@@ -175,13 +336,63 @@ pub fn run_collector(receiver: Receiver<SimpleIpfix>, sender: Sender<Window>, sa
     });
     thread::spawn(move || {
         loop {
-            thread::sleep(Duration::new(30, 0));
+            let now = time_now();
+            let window_end = window_boundary(now, window_length) + window_length;
+            thread::sleep(Duration::from_millis(window_end - now));
             let mut col = (*collector_time).lock().unwrap();
-            (*col).next_window();
+            (*col).next_window_at(window_end);
         }
     });
+    guard
 }
 
+
+/// Cuts windows by sample timestamps instead of the wall clock, used when
+/// reading capture files. The last window is sent once the receiver closes.
+pub fn run_offline_collector(receiver: Receiver<Sample>, sender: Sender<Window>, sampling: u32, window_length: u64) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut collector = WindowCollector::new(sampling, sender);
+        let mut window_end: Option<u64> = None;
+        loop {
+            match receiver.recv() {
+                Ok(sample) => {
+                    monitor::sample_received();
+                    let mut end = match window_end {
+                        Some(end) => end,
+                        None => {
+                            let start = window_boundary(sample.timestamp, window_length);
+                            collector.next_window_at(start);
+                            start + window_length
+                        }
+                    };
+                    // Gaps in the capture are not filled with empty windows.
+                    if sample.timestamp >= end {
+                        let start = window_boundary(sample.timestamp, window_length);
+                        collector.next_window_between(end, start);
+                        end = start + window_length;
+                    }
+                    window_end = Some(end);
+                    collector.add(sample).unwrap();
+                },
+                Err(_) => {
+                    if let Some(end) = window_end {
+                        collector.next_window_at(end);
+                    }
+                    drop(receiver);
+                    break;
+                }
+            };
+        }
+    })
+}
+
+
+/// Start of the window `timestamp` falls into.
+pub fn window_boundary(timestamp: u64, window_length: u64) -> u64 {
+    timestamp - timestamp % window_length
+}
+
+
 pub fn time_now() -> u64 {
     let timespec = time::get_time();
     ((timespec.sec * 1000) as u64) + ((timespec.nsec / 1000000) as u64) 
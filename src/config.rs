@@ -0,0 +1,446 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::net::ToSocketAddrs;
+use std::path::Path;
+use std::process;
+
+use argparse::{ArgumentParser, Collect, StoreFalse, StoreTrue, Store};
+use serde_json;
+use toml;
+
+use rules::Rules;
+
+
+pub const DEFAULT_CONFIG_FILE: &'static str = "/etc/ttl_test/config.json";
+
+
+/// Settings that can be overridden for a single capturing interface.
+#[derive(Debug,Clone,Default,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InterfaceSettings {
+    pub processors: Option<u8>,
+    pub dump: Option<bool>,
+    pub filter: Option<String>,
+}
+
+
+#[derive(Debug,Clone)]
+pub struct Config {
+    pub verbose: bool,
+    pub interfaces: Vec<String>,
+    pub sampling: u32,
+    pub influx_host: String,
+    pub influx_db: String,
+    pub store_influx: bool,
+    pub sinks: Vec<String>,
+    pub influx_username: String,
+    pub influx_password: String,
+    pub influx_retention_policy: String,
+    pub influx_precision: String,
+    pub influx_batch_size: usize,
+    pub influx_retries: u32,
+    pub influx_retry_delay: u64,
+    pub influx_queue_size: usize,
+    pub influx_journal: String,
+    pub cfg_file: String,
+    pub processors: u8,
+    pub filter: String,
+    pub rules: Vec<String>,
+    pub decapsulate: bool,
+    pub mode: String,
+    pub window_length: u64,
+    pub rollups: Vec<u64>,
+    pub rollup_storage: String,
+    pub idle_timeout: u64,
+    pub active_timeout: u64,
+    pub ipfix_target: String,
+    pub ipfix_transport: String,
+    pub ipfix_domain: u32,
+    pub netflow_target: String,
+    pub netflow_version: u8,
+    pub netflow_source_id: u32,
+    pub metrics_listen: String,
+    pub monitor_interval: u64,
+    pub read_file: String,
+    pub dump_dir: String,
+    pub dump_max_size: u64,
+    pub dump_interval: u64,
+    pub dump_files: usize,
+    pub interface_settings: HashMap<String, InterfaceSettings>,
+}
+
+impl Config {
+    fn new() -> Config {
+        Config {
+            verbose: false,
+            interfaces: vec!["lo".to_string()],
+            sampling: 1u32,
+            influx_host: "http://localhost:8086".to_string(),
+            influx_db: "mydb".to_string(),
+            store_influx: true,
+            sinks: vec![],
+            influx_username: "".to_string(),
+            influx_password: "".to_string(),
+            influx_retention_policy: "".to_string(),
+            influx_precision: "ms".to_string(),
+            influx_batch_size: 5000,
+            influx_retries: 3,
+            influx_retry_delay: 500,
+            influx_queue_size: 100,
+            influx_journal: "".to_string(),
+            cfg_file: DEFAULT_CONFIG_FILE.to_string(),
+            processors: 2,
+            filter: "".to_string(),
+            rules: vec![],
+            decapsulate: false,
+            mode: "windows".to_string(),
+            window_length: 30,
+            rollups: vec![],
+            rollup_storage: "measurement".to_string(),
+            idle_timeout: 15,
+            active_timeout: 1800,
+            ipfix_target: "".to_string(),
+            ipfix_transport: "udp".to_string(),
+            ipfix_domain: 0,
+            netflow_target: "".to_string(),
+            netflow_version: 9,
+            netflow_source_id: 0,
+            metrics_listen: "".to_string(),
+            monitor_interval: 60,
+            read_file: "".to_string(),
+            dump_dir: "".to_string(),
+            dump_max_size: 100,
+            dump_interval: 3600,
+            dump_files: 10,
+            interface_settings: HashMap::new()
+        }
+    }
+
+    pub fn interface(&self, name: &str) -> InterfaceSettings {
+        self.interface_settings.get(name).cloned().unwrap_or_default()
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let mut errors = vec![];
+        if self.interfaces.is_empty() && self.read_file.is_empty() {
+            errors.push("no capturing interfaces given".to_string());
+        }
+        for sink in self.sinks.iter() {
+            let known = match sink.as_str() {
+                "influx" | "stdout" | "null" => true,
+                _ => sink.starts_with("csv:") && sink.len() > "csv:".len()
+            };
+            if !known {
+                errors.push(format!("unknown sink `{}`, expected `influx`, `stdout`, `csv:<path>` or `null`", sink));
+            }
+        }
+        if !self.influx_host.starts_with("http://") && !self.influx_host.starts_with("https://") {
+            errors.push(format!("`influx_host` must be an `http://` or `https://` URL, got `{}`", self.influx_host));
+        }
+        if !self.influx_password.is_empty() && self.influx_username.is_empty() {
+            errors.push("`influx_password` is set without `influx_username`".to_string());
+        }
+        match self.influx_precision.as_str() {
+            "s" | "ms" | "us" | "ns" => (),
+            precision => errors.push(format!("`influx_precision` must be `s`, `ms`, `us` or `ns`, got `{}`", precision))
+        }
+        if self.influx_batch_size == 0 {
+            errors.push("`influx_batch_size` must be at least 1".to_string());
+        }
+        if let Err(e) = Rules::parse(&self.rules) {
+            errors.push(e);
+        }
+        if self.processors == 0 {
+            errors.push("`processors` must be at least 1".to_string());
+        }
+        for (name, settings) in self.interface_settings.iter() {
+            if settings.processors == Some(0) {
+                errors.push(format!("`processors` of interface {} must be at least 1", name));
+            }
+        }
+        if self.mode != "windows" && self.mode != "flows" {
+            errors.push(format!("`mode` must be `windows` or `flows`, got `{}`", self.mode));
+        }
+        if self.window_length == 0 {
+            errors.push("`window_length` must be at least 1 second".to_string());
+        }
+        for rollup in self.rollups.iter() {
+            if self.window_length == 0 || *rollup <= self.window_length || rollup % self.window_length != 0 {
+                errors.push(format!("rollup of {} seconds must be a larger multiple of `window_length`", rollup));
+            }
+        }
+        if self.rollup_storage != "measurement" && self.rollup_storage != "retention_policy" {
+            errors.push(format!("`rollup_storage` must be `measurement` or `retention_policy`, got `{}`", self.rollup_storage));
+        }
+        if self.idle_timeout == 0 {
+            errors.push("`idle_timeout` must be at least 1 second".to_string());
+        }
+        if self.active_timeout == 0 {
+            errors.push("`active_timeout` must be at least 1 second".to_string());
+        }
+        if self.ipfix_transport != "udp" && self.ipfix_transport != "tcp" {
+            errors.push(format!("`ipfix_transport` must be `udp` or `tcp`, got `{}`", self.ipfix_transport));
+        }
+        if self.netflow_version != 5 && self.netflow_version != 9 {
+            errors.push(format!("`netflow_version` must be 5 or 9, got {}", self.netflow_version));
+        }
+        // The v5 header keeps the sampling interval in 14 bits.
+        if !self.netflow_target.is_empty() && self.netflow_version == 5 && self.sampling > 0x3fff {
+            errors.push(format!("`sampling` must be at most 16383 for NetFlow v5 export, got {}", self.sampling));
+        }
+        if !self.metrics_listen.is_empty() && self.metrics_listen.to_socket_addrs().is_err() {
+            errors.push(format!("`metrics_listen` must be a host:port address, got `{}`", self.metrics_listen));
+        }
+        if self.dump_files == 0 {
+            errors.push("`dump_files` must be at least 1".to_string());
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("\n"))
+        }
+    }
+}
+
+
+/// Mirror of `Config` as found in the config file, missing keys keep their defaults.
+#[derive(Debug,Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    verbose: Option<bool>,
+    interfaces: Option<Vec<FileInterface>>,
+    sampling: Option<u32>,
+    influx_host: Option<String>,
+    influx_db: Option<String>,
+    store_influx: Option<bool>,
+    sinks: Option<Vec<String>>,
+    influx_username: Option<String>,
+    influx_password: Option<String>,
+    influx_retention_policy: Option<String>,
+    influx_precision: Option<String>,
+    influx_batch_size: Option<usize>,
+    influx_retries: Option<u32>,
+    influx_retry_delay: Option<u64>,
+    influx_queue_size: Option<usize>,
+    influx_journal: Option<String>,
+    processors: Option<u8>,
+    filter: Option<String>,
+    rules: Option<Vec<String>>,
+    decapsulate: Option<bool>,
+    mode: Option<String>,
+    window_length: Option<u64>,
+    rollups: Option<Vec<u64>>,
+    rollup_storage: Option<String>,
+    idle_timeout: Option<u64>,
+    active_timeout: Option<u64>,
+    ipfix_target: Option<String>,
+    ipfix_transport: Option<String>,
+    ipfix_domain: Option<u32>,
+    netflow_target: Option<String>,
+    netflow_version: Option<u8>,
+    netflow_source_id: Option<u32>,
+    metrics_listen: Option<String>,
+    monitor_interval: Option<u64>,
+    read_file: Option<String>,
+    dump_dir: Option<String>,
+    dump_max_size: Option<u64>,
+    dump_interval: Option<u64>,
+    dump_files: Option<usize>,
+}
+
+
+/// An interface is either listed by name or as a table with its own settings.
+#[derive(Debug,Deserialize)]
+#[serde(untagged)]
+enum FileInterface {
+    Name(String),
+    Settings(NamedInterfaceSettings),
+}
+
+
+#[derive(Debug,Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NamedInterfaceSettings {
+    name: String,
+    processors: Option<u8>,
+    dump: Option<bool>,
+    filter: Option<String>,
+}
+
+
+macro_rules! merge {
+    ($cfg:ident, $file:ident, $($field:ident),*) => {
+        $(
+            if let Some(value) = $file.$field {
+                $cfg.$field = value;
+            }
+        )*
+    };
+}
+
+
+impl FileConfig {
+    fn apply(self, cfg: &mut Config) {
+        let file = self;
+        merge!(cfg, file, verbose, sampling, influx_host, influx_db, store_influx, sinks, processors, filter, rules, decapsulate, mode,
+               influx_username, influx_password, influx_retention_policy, influx_precision, influx_batch_size,
+               influx_retries, influx_retry_delay, influx_queue_size, influx_journal,
+               window_length, rollups, rollup_storage, idle_timeout, active_timeout, ipfix_target, ipfix_transport, ipfix_domain,
+               netflow_target, netflow_version, netflow_source_id, metrics_listen, monitor_interval, read_file,
+               dump_dir, dump_max_size, dump_interval, dump_files);
+        if let Some(interfaces) = file.interfaces {
+            cfg.interfaces = vec![];
+            for interface in interfaces {
+                match interface {
+                    FileInterface::Name(name) => cfg.interfaces.push(name),
+                    FileInterface::Settings(settings) => {
+                        cfg.interfaces.push(settings.name.clone());
+                        cfg.interface_settings.insert(settings.name, InterfaceSettings {
+                            processors: settings.processors,
+                            dump: settings.dump,
+                            filter: settings.filter
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
+
+
+/// Files ending with `.toml` are read as TOML, everything else as JSON.
+fn read_file(path: &str) -> Result<FileConfig, String> {
+    let mut content = String::new();
+    let read = File::open(path).and_then(|mut file| { file.read_to_string(&mut content) });
+    if let Err(e) = read {
+        return Err(format!("unable to read config file {}: {}", path, e));
+    }
+    if path.ends_with(".toml") {
+        toml::from_str(&content).map_err(|e| { format!("invalid config file {}: {}", path, e) })
+    } else {
+        serde_json::from_str(&content).map_err(|e| { format!("invalid config file {}: {}", path, e) })
+    }
+}
+
+
+fn parse_args(cfg: &mut Config) {
+    let mut ap = ArgumentParser::new();
+    ap.set_description("Packet capturer and aggregator");
+    ap.refer(&mut cfg.verbose)
+        .add_option(&["-v", "--verbose"], StoreTrue, "Enable verbose mode");
+    ap.refer(&mut cfg.interfaces)
+        .add_argument("INTERFACES", Collect, "Capturing interfaces");
+    ap.refer(&mut cfg.sampling)
+        .add_option(&["-s", "--sampling"], Store, "How much packets are not captured");
+    ap.refer(&mut cfg.influx_host)
+        .add_option(&["-i", "--influx-host"], Store, "Influx host address, `https://` addresses connect over TLS verified against the system trust store");
+    ap.refer(&mut cfg.influx_db)
+        .add_option(&["-d", "--database"], Store, "Influx database name");
+    ap.refer(&mut cfg.store_influx)
+        .add_option(&["--no-influx"], StoreFalse, "Do not store aggregates to Influx");
+    ap.refer(&mut cfg.sinks)
+        .add_option(&["--sink"], Collect, "Where aggregates are stored, `influx`, `stdout`, `csv:<path>` or `null`, may be repeated");
+    ap.refer(&mut cfg.influx_username)
+        .add_option(&["--influx-username"], Store, "Influx user, authentication is disabled when empty");
+    ap.refer(&mut cfg.influx_password)
+        .add_option(&["--influx-password"], Store, "Influx password");
+    ap.refer(&mut cfg.influx_retention_policy)
+        .add_option(&["--influx-retention-policy"], Store, "Influx retention policy, the database default is used when empty");
+    ap.refer(&mut cfg.influx_precision)
+        .add_option(&["--influx-precision"], Store, "Precision of Influx timestamps, `s`, `ms`, `us` or `ns`");
+    ap.refer(&mut cfg.influx_batch_size)
+        .add_option(&["--influx-batch-size"], Store, "Most points sent in a single Influx write, larger batches are split");
+    ap.refer(&mut cfg.influx_retries)
+        .add_option(&["--influx-retries"], Store, "How many times a failed Influx write is retried before it is queued");
+    ap.refer(&mut cfg.influx_retry_delay)
+        .add_option(&["--influx-retry-delay"], Store, "Milliseconds before the first retry, doubled with every next one");
+    ap.refer(&mut cfg.influx_queue_size)
+        .add_option(&["--influx-queue-size"], Store, "How many unwritten batches are kept in memory before they spill to the journal");
+    ap.refer(&mut cfg.influx_journal)
+        .add_option(&["--influx-journal"], Store, "File unwritten batches spill to and are replayed from, batches are dropped when empty");
+    ap.refer(&mut cfg.cfg_file)
+        .add_option(&["-c", "--config"], Store, "Config file path");
+    ap.refer(&mut cfg.processors)
+        .add_option(&["-w", "--workers"], Store, "Specifies how many processors should run");
+    ap.refer(&mut cfg.filter)
+        .add_option(&["-f", "--filter"], Store, "BPF filter in tcpdump syntax for all interfaces and capture files, interfaces may override it");
+    ap.refer(&mut cfg.rules)
+        .add_option(&["--rule"], Collect, "Rule like `deny src=10.0.0.0/8 dst_port=53 proto=UDP` or `tag:web dst_port=80-443`, may be repeated");
+    ap.refer(&mut cfg.decapsulate)
+        .add_option(&["--decapsulate"], StoreTrue, "Record the inner packets of GRE, VXLAN, GENEVE and IP-in-IP tunnels");
+    ap.refer(&mut cfg.mode)
+        .add_option(&["-m", "--mode"], Store, "Aggregation mode, `windows` or `flows`");
+    ap.refer(&mut cfg.window_length)
+        .add_option(&["--window-length"], Store, "Seconds covered by a window, windows end on multiples of it");
+    ap.refer(&mut cfg.rollups)
+        .add_option(&["--rollup"], Collect, "Seconds of a rollup stored next to the windows, may be repeated");
+    ap.refer(&mut cfg.rollup_storage)
+        .add_option(&["--rollup-storage"], Store, "Where rollups are stored, `measurement` suffixed with the rollup length or `retention_policy` named `rollup_<length>`");
+    ap.refer(&mut cfg.idle_timeout)
+        .add_option(&["--idle-timeout"], Store, "Seconds of inactivity after which a flow is exported");
+    ap.refer(&mut cfg.active_timeout)
+        .add_option(&["--active-timeout"], Store, "Seconds after which a long running flow is exported");
+    ap.refer(&mut cfg.ipfix_target)
+        .add_option(&["--ipfix-target"], Store, "IPFIX collector address (host:port), export is disabled when empty");
+    ap.refer(&mut cfg.ipfix_transport)
+        .add_option(&["--ipfix-transport"], Store, "IPFIX transport protocol, `udp` or `tcp`");
+    ap.refer(&mut cfg.ipfix_domain)
+        .add_option(&["--ipfix-domain"], Store, "IPFIX observation domain id");
+    ap.refer(&mut cfg.netflow_target)
+        .add_option(&["--netflow-target"], Store, "NetFlow collector address (host:port), export is disabled when empty");
+    ap.refer(&mut cfg.netflow_version)
+        .add_option(&["--netflow-version"], Store, "NetFlow version, `5` or `9`");
+    ap.refer(&mut cfg.netflow_source_id)
+        .add_option(&["--netflow-source-id"], Store, "NetFlow v9 source id, v5 engine id uses its lowest byte");
+    ap.refer(&mut cfg.metrics_listen)
+        .add_option(&["--metrics-listen"], Store, "Address (host:port) serving Prometheus metrics on `/metrics`, disabled when empty");
+    ap.refer(&mut cfg.monitor_interval)
+        .add_option(&["--monitor-interval"], Store, "Seconds between pipeline counter reports to the sinks, 0 disables");
+    ap.refer(&mut cfg.read_file)
+        .add_option(&["-r", "--read"], Store, "Read packets from a pcap or pcapng file instead of the interfaces");
+    ap.refer(&mut cfg.dump_dir)
+        .add_option(&["--dump-dir"], Store, "Directory for pcap dumps of sampled frames, dumping is disabled when empty");
+    ap.refer(&mut cfg.dump_max_size)
+        .add_option(&["--dump-max-size"], Store, "Megabytes after which a dump file is rotated, 0 disables");
+    ap.refer(&mut cfg.dump_interval)
+        .add_option(&["--dump-interval"], Store, "Seconds after which a dump file is rotated, 0 disables");
+    ap.refer(&mut cfg.dump_files)
+        .add_option(&["--dump-files"], Store, "How many dump files are kept per interface");
+    ap.parse_args_or_exit();
+}
+
+
+/// Defaults are overridden by the config file and the file by command line flags,
+/// `--sink` and `--rollup` replace the lists of the file rather than adding to them
+/// and `influx` is used when no sink is given.
+/// A missing file is only an error when it was asked for with `--config`.
+pub fn load() -> Result<Config, String> {
+    let mut cfg = Config::new();
+    let mut args = cfg.clone();
+    parse_args(&mut args);
+    cfg.cfg_file = args.cfg_file;
+    if cfg.cfg_file != DEFAULT_CONFIG_FILE || Path::new(&cfg.cfg_file).exists() {
+        read_file(&cfg.cfg_file)?.apply(&mut cfg);
+    }
+    parse_args(&mut cfg);
+    if cfg.sinks.is_empty() {
+        cfg.sinks.push("influx".to_string());
+    }
+    if !cfg.store_influx {
+        cfg.sinks.retain(|sink| { sink != "influx" });
+    }
+    cfg.validate()?;
+    Ok(cfg)
+}
+
+
+pub fn load_or_exit() -> Config {
+    match load() {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            eprintln!("Invalid configuration:\n{}", e);
+            process::exit(2);
+        }
+    }
+}
@@ -1,58 +1,263 @@
+use std::cmp;
 use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use collector::{time_now, Sample};
+use flow_key::{Attribute, Protocol};
+use monitor;
+
+/// How often, in milliseconds, flows are checked for expiry.
+const EXPIRY_INTERVAL: u64 = 1000;
 
 #[derive(Clone, Debug)]
-struct Flow {
-    source: String,
-    destination: String,
-    protocol: &'static str,
-    size: u32,
-    pkt_count: u32,
-    update: Vec<HashMap<&'static str, String>>
-    start_time: u64
-    end_time: u64,
+pub struct Flow {
+    pub source: IpAddr,
+    pub destination: IpAddr,
+    pub source_port: Option<u16>,
+    pub destination_port: Option<u16>,
+    pub protocol: Protocol,
+    pub size: u64,
+    pub pkt_count: u32,
+    pub reverse_size: u64,
+    pub reverse_pkt_count: u32,
+    pub min_ttl: u8,
+    pub max_ttl: u8,
+    /// Zero until a packet was seen in the reverse direction.
+    pub reverse_min_ttl: u8,
+    pub reverse_max_ttl: u8,
+    pub attributes: Vec<Attribute>,
+    /// Packets that carried each of `attributes`, in the same order, scaled by sampling.
+    pub attribute_packets: Vec<u32>,
+    pub start_time: u64,
+    pub end_time: u64,
 }
 
-type FlowHeader = (String, String, &'static str);
-type FlowOptions = (u32, u32, Vec<HashMap<&'static str, String>>, u64, u64);
-type FlowUpdate = (u32, u32, HashMap<&'static str, String>);
-const THRITY_SECONDS: u64 = 30000000000;
+impl Flow {
+    fn new(sample: Sample, sampling: u32, timestamp: u64) -> Flow {
+        let key = sample.key;
+        Flow {
+            source: key.source,
+            destination: key.destination,
+            source_port: key.source_port,
+            destination_port: key.destination_port,
+            protocol: key.protocol,
+            size: sample.length as u64 * sampling as u64,
+            pkt_count: sampling,
+            reverse_size: 0u64,
+            reverse_pkt_count: 0u32,
+            min_ttl: sample.ttl,
+            max_ttl: sample.ttl,
+            reverse_min_ttl: 0u8,
+            reverse_max_ttl: 0u8,
+            attribute_packets: vec![sampling; key.attributes.len()],
+            attributes: key.attributes,
+            start_time: timestamp,
+            end_time: timestamp
+        }
+    }
 
+    fn update(&mut self, sample: Sample, sampling: u32, timestamp: u64) {
+        let size = sample.length as u64 * sampling as u64;
+        if (sample.key.source, sample.key.source_port) == (self.source, self.source_port) {
+            self.size += size;
+            self.pkt_count += sampling;
+            self.min_ttl = cmp::min(self.min_ttl, sample.ttl);
+            self.max_ttl = cmp::max(self.max_ttl, sample.ttl);
+        } else {
+            if self.reverse_pkt_count == 0 {
+                self.reverse_min_ttl = sample.ttl;
+                self.reverse_max_ttl = sample.ttl;
+            }
+            self.reverse_size += size;
+            self.reverse_pkt_count += sampling;
+            self.reverse_min_ttl = cmp::min(self.reverse_min_ttl, sample.ttl);
+            self.reverse_max_ttl = cmp::max(self.reverse_max_ttl, sample.ttl);
+        }
+        for attribute in sample.key.attributes {
+            match self.attributes.iter().position(|known| { *known == attribute }) {
+                Some(index) => self.attribute_packets[index] += sampling,
+                None => {
+                    self.attributes.push(attribute);
+                    self.attribute_packets.push(sampling);
+                }
+            }
+        }
+        self.end_time = cmp::max(self.end_time, timestamp);
+    }
 
-struct FlowCollector {
-    flows: HashMap<FlowHeader, FlowOptions>,
-    flow_timeout: u64,
+    pub fn duration(&self) -> u64 {
+        self.end_time - self.start_time
+    }
+}
+
+/// Both directions of a conversation share one header, endpoints are stored ordered.
+/// Conversations on different VLANs or tunnels are kept apart.
+type FlowHeader = ((IpAddr, Option<u16>), (IpAddr, Option<u16>), Protocol, Vec<Attribute>);
+
+fn flow_header(sample: &Sample) -> FlowHeader {
+    let source = (sample.key.source, sample.key.source_port);
+    let destination = (sample.key.destination, sample.key.destination_port);
+    let path = sample.key.attributes.iter()
+        .filter(|attribute| {
+            match **attribute {
+                Attribute::Vlans(_) | Attribute::Tunnel(_) | Attribute::TunnelId(_) => true,
+                _ => false
+            }
+        })
+        .cloned()
+        .collect();
+    if source <= destination {
+        (source, destination, sample.key.protocol, path)
+    } else {
+        (destination, source, sample.key.protocol, path)
+    }
+}
+
+
+pub struct FlowCollector {
+    flows: HashMap<FlowHeader, Flow>,
+    sampling: u32,
+    idle_timeout: u64,
+    active_timeout: u64,
 }
 
 
 impl FlowCollector {
-    pub fn new(timeout: u64) -> FlowCollector {
+    pub fn new(sampling: u32, idle_timeout: u64, active_timeout: u64) -> FlowCollector {
+        let smpl = if sampling < 2u32 {
+            1u32
+        } else {
+            sampling
+        };
         FlowCollector {
             flows: HashMap::new(),
-            flow_timeout: timeout
-        }
-    }
-
-    pub fn add(&mut self, header: FlowHeader, values: FlowUpdate) {
-        match self.flows.get_mut(&header) {
-            Some(options) => {
-                *options.0 += values.0;
-                *options.1 += values.1;
-                *options.4 = time::precise_time_ns();
-                (*options).2.push(values.2);
-            },
-            None => {
-                let cap_time = time::precise_time_ns();
-                let opts: FlowOptions = (values.0, values.1, vec![values.2], cap_time, cap_time);
-                self.flows.insert(header, opts);
-            }
-        };
+            sampling: smpl,
+            idle_timeout: idle_timeout,
+            active_timeout: active_timeout
+        }
+    }
+
+    pub fn add(&mut self, sample: Sample) {
+        let timestamp = sample.timestamp;
+        let sampling = self.sampling;
+        let header = flow_header(&sample);
+        if let Some(flow) = self.flows.get_mut(&header) {
+            flow.update(sample, sampling, timestamp);
+            return;
+        }
+        self.flows.insert(header, Flow::new(sample, sampling, timestamp));
     }
 
-    pub fn collect_finished(&mut self) -> Vec<Flow> {
-        let current_time = time::precise_time_ns();
-        let expired_keys = self.flows.into_iter()
-            .filter(|&(key, value)| { value.4 + THRITY_SECONDS < current_time })
-            .map(|(key, value)| { key })
+    /// Removes and returns flows that were idle longer than the idle timeout
+    /// or have been active longer than the active timeout.
+    pub fn collect_finished(&mut self, current_time: u64) -> Vec<Flow> {
+        let idle_timeout = self.idle_timeout;
+        let active_timeout = self.active_timeout;
+        let expired_keys: Vec<FlowHeader> = self.flows.iter()
+            .filter(|&(_, flow)| {
+                flow.end_time + idle_timeout <= current_time || flow.start_time + active_timeout <= current_time
+            })
+            .map(|(key, _)| { key.clone() })
             .collect();
+        expired_keys.into_iter()
+            .filter_map(|key| { self.flows.remove(&key) })
+            .collect()
+    }
+
+    pub fn collect_all(&mut self) -> Vec<Flow> {
+        self.flows.drain()
+            .map(|(_, flow)| { flow })
+            .collect()
     }
 }
+
+
+pub fn run_flow_collector(receiver: Receiver<Sample>,
+                          sender: Sender<Vec<Flow>>,
+                          sampling: u32,
+                          idle_timeout: u64,
+                          active_timeout: u64) -> thread::JoinHandle<()> {
+    let collector = Arc::new(Mutex::new(FlowCollector::new(sampling, idle_timeout, active_timeout)));
+    let collector_loop = collector.clone();
+    let collector_time = collector.clone();
+    let sender_loop = sender.clone();
+
+    let guard = thread::spawn(move || {
+        loop {
+            match receiver.recv() {
+                Ok(sample) => {
+                    monitor::sample_received();
+                    let mut collector_guard = (*collector_loop).lock().unwrap();
+                    (*collector_guard).add(sample);
+                },
+                Err(e) => {
+                    error!("Flow collector receiver error occured: {}", e);
+                    drop(receiver);
+                    let mut collector_guard = (*collector_loop).lock().unwrap();
+                    let flows = (*collector_guard).collect_all();
+                    if !flows.is_empty() {
+                        sender_loop.send(flows).unwrap();
+                    }
+                    break;
+                }
+            };
+        }
+    });
+    thread::spawn(move || {
+        loop {
+            thread::sleep(Duration::from_millis(EXPIRY_INTERVAL));
+            // Flows have no windows to carry kernel statistics, only the totals are kept.
+            monitor::read_kernel_statistics();
+            let flows = {
+                let mut col = (*collector_time).lock().unwrap();
+                (*col).collect_finished(time_now())
+            };
+            if !flows.is_empty() {
+                sender.send(flows).unwrap();
+            }
+        }
+    });
+    guard
+}
+
+
+/// Expires flows by sample timestamps instead of the wall clock, used when
+/// reading capture files. Remaining flows are sent once the receiver closes.
+pub fn run_offline_flow_collector(receiver: Receiver<Sample>,
+                                  sender: Sender<Vec<Flow>>,
+                                  sampling: u32,
+                                  idle_timeout: u64,
+                                  active_timeout: u64) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut collector = FlowCollector::new(sampling, idle_timeout, active_timeout);
+        let mut last_check = 0u64;
+        loop {
+            match receiver.recv() {
+                Ok(sample) => {
+                    monitor::sample_received();
+                    let timestamp = sample.timestamp;
+                    collector.add(sample);
+                    if timestamp >= last_check + EXPIRY_INTERVAL {
+                        last_check = timestamp;
+                        let flows = collector.collect_finished(timestamp);
+                        if !flows.is_empty() {
+                            sender.send(flows).unwrap();
+                        }
+                    }
+                },
+                Err(_) => {
+                    drop(receiver);
+                    let flows = collector.collect_all();
+                    if !flows.is_empty() {
+                        sender.send(flows).unwrap();
+                    }
+                    break;
+                }
+            };
+        }
+    })
+}
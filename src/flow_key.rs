@@ -0,0 +1,211 @@
+use std::fmt;
+use std::net::IpAddr;
+
+use ipv6::extension_name;
+use tunnel::TunnelType;
+
+
+/// FIN, SYN, RST, PSH, ACK and URG, other TCP flags are not kept.
+pub const TCP_FLAG_MASK: u16 = 0x3f;
+
+static TCP_FLAGS: [(u16, &'static str); 6] = [
+    (0x02, "SYN"),
+    (0x01, "FIN"),
+    (0x10, "ACK"),
+    (0x04, "RST"),
+    (0x08, "PSH"),
+    (0x20, "URG"),
+];
+
+
+/// Transport protocols samples are taken of.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash,PartialOrd,Ord)]
+pub enum Protocol {
+    Icmp,
+    Tcp,
+    Udp,
+    Icmpv6,
+}
+
+impl Protocol {
+    /// Case insensitive, `tcp` and `TCP` are the same protocol.
+    pub fn from_name(name: &str) -> Option<Protocol> {
+        match name.to_uppercase().as_str() {
+            "ICMP" => Some(Protocol::Icmp),
+            "TCP" => Some(Protocol::Tcp),
+            "UDP" => Some(Protocol::Udp),
+            "ICMPV6" => Some(Protocol::Icmpv6),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Protocol::Icmp => "ICMP",
+            Protocol::Tcp => "TCP",
+            Protocol::Udp => "UDP",
+            Protocol::Icmpv6 => "ICMPv6"
+        }
+    }
+
+    /// IANA protocol number.
+    pub fn number(&self) -> u8 {
+        match *self {
+            Protocol::Icmp => 1,
+            Protocol::Tcp => 6,
+            Protocol::Udp => 17,
+            Protocol::Icmpv6 => 58
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+
+#[derive(Debug,Clone,PartialEq,Eq,Hash)]
+pub enum Attribute {
+    TcpFlags(u16),
+    IcmpType(u8),
+    IcmpCode(u8),
+    /// VLAN IDs of tagged frames, outermost first.
+    Vlans(Vec<u16>),
+    /// IPv6 extension headers in the order they were passed.
+    Ipv6Extensions(Vec<u8>),
+    Tunnel(TunnelType),
+    /// VXLAN or GENEVE VNI, GRE key.
+    TunnelId(u32),
+    /// Comma separated labels of the tag rules a sample matched.
+    Tag(String),
+}
+
+impl Attribute {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Attribute::TcpFlags(_) => "flags",
+            Attribute::IcmpType(_) => "type",
+            Attribute::IcmpCode(_) => "code",
+            Attribute::Vlans(_) => "vlan",
+            Attribute::Ipv6Extensions(_) => "ipv6_ext",
+            Attribute::Tunnel(_) => "tunnel",
+            Attribute::TunnelId(_) => "tunnel_id",
+            Attribute::Tag(_) => "tag"
+        }
+    }
+
+    /// Value as written to sinks, ICMP type names depend on the protocol.
+    pub fn value(&self, protocol: Protocol) -> String {
+        match *self {
+            Attribute::TcpFlags(flags) => tcp_flag_names(flags),
+            Attribute::IcmpType(icmp_type) => match icmp_type_name(protocol, icmp_type) {
+                Some(name) => name.to_string(),
+                None => format!("{}", icmp_type)
+            },
+            Attribute::IcmpCode(code) => format!("{}", code),
+            Attribute::Vlans(ref vlans) => {
+                vlans.iter()
+                    .map(|vlan| { format!("{}", vlan) })
+                    .collect::<Vec<String>>()
+                    .join(",")
+            },
+            Attribute::Ipv6Extensions(ref extensions) => {
+                extensions.iter()
+                    .map(|&extension| {
+                        match extension_name(extension) {
+                            Some(name) => name.to_string(),
+                            None => format!("{}", extension)
+                        }
+                    })
+                    .collect::<Vec<String>>()
+                    .join(",")
+            },
+            Attribute::Tunnel(tunnel) => tunnel.name().to_string(),
+            Attribute::TunnelId(id) => format!("{}", id),
+            Attribute::Tag(ref labels) => labels.clone()
+        }
+    }
+}
+
+
+/// Aggregation key of samples, ports are only known for TCP and UDP.
+#[derive(Debug,Clone,PartialEq,Eq,Hash)]
+pub struct FlowKey {
+    pub source: IpAddr,
+    pub destination: IpAddr,
+    pub source_port: Option<u16>,
+    pub destination_port: Option<u16>,
+    pub protocol: Protocol,
+    pub attributes: Vec<Attribute>,
+}
+
+impl FlowKey {
+    pub fn attribute_values(&self) -> Vec<(&'static str, String)> {
+        attribute_values(self.protocol, &self.attributes)
+    }
+}
+
+
+pub fn attribute_values(protocol: Protocol, attributes: &[Attribute]) -> Vec<(&'static str, String)> {
+    attributes.iter()
+        .map(|attribute| { (attribute.name(), attribute.value(protocol)) })
+        .collect()
+}
+
+
+/// Port as a tag value, `-` for protocols without ports.
+pub fn port_name(port: Option<u16>) -> String {
+    match port {
+        Some(port) => format!("{}", port),
+        None => "-".to_string()
+    }
+}
+
+
+pub fn tcp_flag_names(flags: u16) -> String {
+    TCP_FLAGS.iter()
+        .filter(|&&(flag, _)| { flags & flag == flag })
+        .map(|&(_, name)| { name })
+        .collect::<Vec<&str>>()
+        .join(",")
+}
+
+
+fn icmp_type_name(protocol: Protocol, icmp_type: u8) -> Option<&'static str> {
+    match (protocol, icmp_type) {
+        (Protocol::Icmp, 0) => Some("EchoReply"),
+        (Protocol::Icmp, 3) => Some("DestinationUnreachable"),
+        (Protocol::Icmp, 4) => Some("SourceQuench"),
+        (Protocol::Icmp, 5) => Some("RedirectMessage"),
+        (Protocol::Icmp, 8) => Some("EchoRequest"),
+        (Protocol::Icmp, 9) => Some("RouterAdvertisement"),
+        (Protocol::Icmp, 10) => Some("RouterSolicitation"),
+        (Protocol::Icmp, 11) => Some("TimeExceeded"),
+        (Protocol::Icmp, 12) => Some("ParameterProblem"),
+        (Protocol::Icmp, 13) => Some("Timestamp"),
+        (Protocol::Icmp, 14) => Some("TimestampReply"),
+        (Protocol::Icmp, 15) => Some("InformationRequest"),
+        (Protocol::Icmp, 16) => Some("InformationReply"),
+        (Protocol::Icmp, 17) => Some("AddressMaskRequest"),
+        (Protocol::Icmp, 18) => Some("AddressMaskReply"),
+        (Protocol::Icmp, 30) => Some("Traceroute"),
+        (Protocol::Icmpv6, 1) => Some("DestinationUnreachable"),
+        (Protocol::Icmpv6, 2) => Some("PacketTooBig"),
+        (Protocol::Icmpv6, 3) => Some("TimeExceeded"),
+        (Protocol::Icmpv6, 4) => Some("ParameterProblem"),
+        (Protocol::Icmpv6, 128) => Some("EchoRequest"),
+        (Protocol::Icmpv6, 129) => Some("EchoReply"),
+        (Protocol::Icmpv6, 130) => Some("MulticastListenerQuery"),
+        (Protocol::Icmpv6, 131) => Some("MulticastListenerReport"),
+        (Protocol::Icmpv6, 132) => Some("MulticastListenerDone"),
+        (Protocol::Icmpv6, 133) => Some("RouterSolicitation"),
+        (Protocol::Icmpv6, 134) => Some("RouterAdvertisement"),
+        (Protocol::Icmpv6, 135) => Some("NeighborSolicitation"),
+        (Protocol::Icmpv6, 136) => Some("NeighborAdvertisement"),
+        (Protocol::Icmpv6, 137) => Some("Redirect"),
+        (Protocol::Icmpv6, 143) => Some("MulticastListenerReportV2"),
+        _ => None
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use flow_key::FlowKey;
+
+
+/// Milliseconds a datagram waits for its remaining fragments, as in Linux `ipfrag_time`.
+const FRAGMENT_TIMEOUT: u64 = 30000;
+
+
+/// Position of a packet within a fragmented datagram.
+#[derive(Debug,Clone,Copy)]
+pub struct Fragment {
+    pub id: u32,
+    /// In 8 octet units.
+    pub offset: u16,
+}
+
+impl Fragment {
+    /// Only the first fragment carries the transport header.
+    pub fn is_first(&self) -> bool {
+        self.offset == 0
+    }
+}
+
+
+/// Source, destination, protocol and identification of a datagram. IPv6
+/// datagrams are identified without the protocol, as in RFC 8200.
+pub type Datagram = (IpAddr, IpAddr, Option<u8>, u32);
+
+
+/// Flow keys of first fragments, so that the fragments following them can be
+/// attributed to the same flow without reassembling the datagram. Fragments
+/// arriving before their first fragment are not attributed. Keys are kept until
+/// they time out since fragments may arrive out of order or be duplicated.
+#[derive(Debug,Default)]
+pub struct FragmentTable {
+    keys: HashMap<Datagram, (FlowKey, u64)>,
+    last_expiry: u64,
+}
+
+impl FragmentTable {
+    pub fn insert(&mut self, datagram: Datagram, key: FlowKey, timestamp: u64) -> () {
+        if timestamp >= self.last_expiry + FRAGMENT_TIMEOUT {
+            self.keys.retain(|_, &mut (_, seen)| { seen + FRAGMENT_TIMEOUT > timestamp });
+            self.last_expiry = timestamp;
+        }
+        self.keys.insert(datagram, (key, timestamp));
+    }
+
+    /// Key of the first fragment of `datagram`, unless it timed out before `timestamp`.
+    pub fn attribute(&self, datagram: &Datagram, timestamp: u64) -> Option<FlowKey> {
+        match self.keys.get(datagram) {
+            Some(&(ref key, seen)) if seen + FRAGMENT_TIMEOUT > timestamp => Some(key.clone()),
+            _ => None
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use flow_key::{FlowKey, Protocol};
+
+    use super::{Datagram, FragmentTable, FRAGMENT_TIMEOUT};
+
+    fn datagram(id: u32) -> Datagram {
+        (IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)), Some(17), id)
+    }
+
+    fn key(source_port: u16) -> FlowKey {
+        FlowKey {
+            source: IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+            destination: IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)),
+            source_port: Some(source_port),
+            destination_port: Some(53),
+            protocol: Protocol::Udp,
+            attributes: vec![]
+        }
+    }
+
+    #[test]
+    fn attributes_fragments_following_the_first() {
+        let mut table = FragmentTable::default();
+        table.insert(datagram(1), key(1000), 1000);
+        assert_eq!(table.attribute(&datagram(1), 1010), Some(key(1000)));
+        assert_eq!(table.attribute(&datagram(1), 1020), Some(key(1000)));
+        assert_eq!(table.attribute(&datagram(2), 1020), None);
+    }
+
+    #[test]
+    fn does_not_attribute_fragments_before_the_first() {
+        let mut table = FragmentTable::default();
+        assert_eq!(table.attribute(&datagram(1), 1000), None);
+        table.insert(datagram(1), key(1000), 1010);
+        assert_eq!(table.attribute(&datagram(1), 1020), Some(key(1000)));
+    }
+
+    #[test]
+    fn does_not_attribute_expired_fragments() {
+        let mut table = FragmentTable::default();
+        table.insert(datagram(1), key(1000), 1000);
+        assert_eq!(table.attribute(&datagram(1), 1000 + FRAGMENT_TIMEOUT - 1), Some(key(1000)));
+        assert_eq!(table.attribute(&datagram(1), 1000 + FRAGMENT_TIMEOUT), None);
+    }
+
+    #[test]
+    fn expires_keys_on_insert() {
+        let mut table = FragmentTable::default();
+        table.insert(datagram(1), key(1000), 1000);
+        table.insert(datagram(2), key(2000), 1000 + FRAGMENT_TIMEOUT);
+        assert!(!table.keys.contains_key(&datagram(1)));
+        assert_eq!(table.attribute(&datagram(2), 1000 + FRAGMENT_TIMEOUT), Some(key(2000)));
+    }
+
+    #[test]
+    fn reused_identification_replaces_the_key() {
+        let mut table = FragmentTable::default();
+        table.insert(datagram(1), key(1000), 1000);
+        table.insert(datagram(1), key(2000), 2000);
+        assert_eq!(table.attribute(&datagram(1), 2010), Some(key(2000)));
+    }
+}
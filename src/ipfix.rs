@@ -0,0 +1,463 @@
+use std::io::Write;
+use std::net::{IpAddr, TcpStream, ToSocketAddrs, UdpSocket, SocketAddr};
+use std::sync::mpsc::Receiver;
+use std::thread;
+
+use collector::{time_now, Stats, Window};
+use flow_collector::Flow;
+use flow_key::{Attribute, FlowKey};
+
+
+const IPFIX_VERSION: u16 = 10;
+const MESSAGE_HEADER_LENGTH: usize = 16;
+const SET_HEADER_LENGTH: usize = 4;
+const TEMPLATE_SET_ID: u16 = 2;
+/// Keeps a single message inside a common ethernet MTU when exporting over UDP.
+const MAX_MESSAGE_LENGTH: usize = 1400;
+
+// Information elements, see https://www.iana.org/assignments/ipfix/ipfix.xhtml
+const PACKET_DELTA_COUNT: u16 = 2;
+const OCTET_DELTA_COUNT: u16 = 1;
+const PROTOCOL_IDENTIFIER: u16 = 4;
+const TCP_CONTROL_BITS: u16 = 6;
+const SOURCE_TRANSPORT_PORT: u16 = 7;
+const SOURCE_IPV4_ADDRESS: u16 = 8;
+const DESTINATION_TRANSPORT_PORT: u16 = 11;
+const DESTINATION_IPV4_ADDRESS: u16 = 12;
+const SOURCE_IPV6_ADDRESS: u16 = 27;
+const DESTINATION_IPV6_ADDRESS: u16 = 28;
+const MINIMUM_TTL: u16 = 52;
+const MAXIMUM_TTL: u16 = 53;
+const FLOW_START_MILLISECONDS: u16 = 152;
+const FLOW_END_MILLISECONDS: u16 = 153;
+const BIFLOW_DIRECTION: u16 = 239;
+
+// biflowDirection values, the source of a flow is the endpoint that sent its first packet.
+const INITIATOR: u8 = 1;
+const REVERSE_INITIATOR: u8 = 2;
+
+const WINDOW_IPV4_TEMPLATE: u16 = 256;
+const WINDOW_IPV6_TEMPLATE: u16 = 257;
+const FLOW_IPV4_TEMPLATE: u16 = 258;
+const FLOW_IPV6_TEMPLATE: u16 = 259;
+
+static WINDOW_IPV4_FIELDS: [(u16, u16); 12] = [
+    (SOURCE_IPV4_ADDRESS, 4),
+    (DESTINATION_IPV4_ADDRESS, 4),
+    (SOURCE_TRANSPORT_PORT, 2),
+    (DESTINATION_TRANSPORT_PORT, 2),
+    (PROTOCOL_IDENTIFIER, 1),
+    (TCP_CONTROL_BITS, 2),
+    (PACKET_DELTA_COUNT, 8),
+    (OCTET_DELTA_COUNT, 8),
+    (MINIMUM_TTL, 1),
+    (MAXIMUM_TTL, 1),
+    (FLOW_START_MILLISECONDS, 8),
+    (FLOW_END_MILLISECONDS, 8),
+];
+
+static WINDOW_IPV6_FIELDS: [(u16, u16); 12] = [
+    (SOURCE_IPV6_ADDRESS, 16),
+    (DESTINATION_IPV6_ADDRESS, 16),
+    (SOURCE_TRANSPORT_PORT, 2),
+    (DESTINATION_TRANSPORT_PORT, 2),
+    (PROTOCOL_IDENTIFIER, 1),
+    (TCP_CONTROL_BITS, 2),
+    (PACKET_DELTA_COUNT, 8),
+    (OCTET_DELTA_COUNT, 8),
+    (MINIMUM_TTL, 1),
+    (MAXIMUM_TTL, 1),
+    (FLOW_START_MILLISECONDS, 8),
+    (FLOW_END_MILLISECONDS, 8),
+];
+
+/// Flows are exported as one record per direction, told apart by `BIFLOW_DIRECTION`.
+static FLOW_IPV4_FIELDS: [(u16, u16); 13] = [
+    (SOURCE_IPV4_ADDRESS, 4),
+    (DESTINATION_IPV4_ADDRESS, 4),
+    (SOURCE_TRANSPORT_PORT, 2),
+    (DESTINATION_TRANSPORT_PORT, 2),
+    (PROTOCOL_IDENTIFIER, 1),
+    (TCP_CONTROL_BITS, 2),
+    (PACKET_DELTA_COUNT, 8),
+    (OCTET_DELTA_COUNT, 8),
+    (MINIMUM_TTL, 1),
+    (MAXIMUM_TTL, 1),
+    (FLOW_START_MILLISECONDS, 8),
+    (FLOW_END_MILLISECONDS, 8),
+    (BIFLOW_DIRECTION, 1),
+];
+
+static FLOW_IPV6_FIELDS: [(u16, u16); 13] = [
+    (SOURCE_IPV6_ADDRESS, 16),
+    (DESTINATION_IPV6_ADDRESS, 16),
+    (SOURCE_TRANSPORT_PORT, 2),
+    (DESTINATION_TRANSPORT_PORT, 2),
+    (PROTOCOL_IDENTIFIER, 1),
+    (TCP_CONTROL_BITS, 2),
+    (PACKET_DELTA_COUNT, 8),
+    (OCTET_DELTA_COUNT, 8),
+    (MINIMUM_TTL, 1),
+    (MAXIMUM_TTL, 1),
+    (FLOW_START_MILLISECONDS, 8),
+    (FLOW_END_MILLISECONDS, 8),
+    (BIFLOW_DIRECTION, 1),
+];
+
+static TEMPLATES: [(u16, &'static [(u16, u16)]); 4] = [
+    (WINDOW_IPV4_TEMPLATE, &WINDOW_IPV4_FIELDS),
+    (WINDOW_IPV6_TEMPLATE, &WINDOW_IPV6_FIELDS),
+    (FLOW_IPV4_TEMPLATE, &FLOW_IPV4_FIELDS),
+    (FLOW_IPV6_TEMPLATE, &FLOW_IPV6_FIELDS),
+];
+
+
+enum Transport {
+    Udp(UdpSocket, SocketAddr),
+    Tcp(Option<TcpStream>, SocketAddr),
+}
+
+
+pub struct IpfixExporter {
+    transport: Transport,
+    observation_domain: u32,
+    sequence: u32,
+}
+
+impl IpfixExporter {
+    pub fn new(target: &str, protocol: &str, observation_domain: u32) -> Result<IpfixExporter, String> {
+        let address = match target.to_socket_addrs() {
+            Ok(mut addresses) => match addresses.next() {
+                Some(address) => address,
+                None => return Err(format!("Unable to resolve IPFIX collector {}", target))
+            },
+            Err(e) => return Err(format!("Unable to resolve IPFIX collector {}: {}", target, e))
+        };
+        let transport = match protocol {
+            "udp" => {
+                let bind_address = if address.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+                match UdpSocket::bind(bind_address) {
+                    Ok(socket) => Transport::Udp(socket, address),
+                    Err(e) => return Err(format!("Unable to open IPFIX socket: {}", e))
+                }
+            },
+            "tcp" => Transport::Tcp(None, address),
+            _ => return Err(format!("Unknown IPFIX transport: {}", protocol))
+        };
+        Ok(IpfixExporter {
+            transport: transport,
+            observation_domain: observation_domain,
+            sequence: 0u32
+        })
+    }
+
+    pub fn export_window(&mut self, window: &Window) -> Result<(), String> {
+        let (start_time, end_time) = (window.start_time(), window.end_time());
+        let records = window.iter()
+            .filter_map(|(key, stats)| { encode_window_record(key, stats, start_time, end_time) })
+            .collect();
+        self.send_records(records)
+    }
+
+    pub fn export_flows(&mut self, flows: &[Flow]) -> Result<(), String> {
+        let mut records = vec![];
+        for flow in flows {
+            records.extend(encode_flow_records(flow));
+        }
+        self.send_records(records)
+    }
+
+    fn send_records(&mut self, mut records: Vec<(u16, Vec<u8>)>) -> Result<(), String> {
+        records.sort_by_key(|&(template_id, _)| { template_id });
+        let mut message = MessageBuilder::new();
+        if self.needs_templates() {
+            message.add_templates();
+        }
+        let mut record_count = 0u32;
+        for (template_id, record) in records {
+            if !message.fits(template_id, &record) {
+                self.send_message(message.finish(self.sequence, self.observation_domain))?;
+                self.sequence = self.sequence.wrapping_add(record_count);
+                record_count = 0u32;
+                message = MessageBuilder::new();
+                if self.needs_templates() {
+                    message.add_templates();
+                }
+            }
+            message.add_record(template_id, record);
+            record_count += 1;
+        }
+        if record_count > 0 {
+            self.send_message(message.finish(self.sequence, self.observation_domain))?;
+            self.sequence = self.sequence.wrapping_add(record_count);
+        }
+        Ok(())
+    }
+
+    /// UDP collectors may miss templates so every message carries them,
+    /// over TCP they are sent once per connection.
+    fn needs_templates(&self) -> bool {
+        match self.transport {
+            Transport::Udp(_, _) => true,
+            Transport::Tcp(ref stream, _) => stream.is_none()
+        }
+    }
+
+    fn send_message(&mut self, message: Vec<u8>) -> Result<(), String> {
+        match self.transport {
+            Transport::Udp(ref socket, address) => {
+                match socket.send_to(&message, address) {
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(format!("Unable to send IPFIX message: {}", e))
+                }
+            },
+            Transport::Tcp(ref mut stream, address) => {
+                if stream.is_none() {
+                    match TcpStream::connect(address) {
+                        Ok(connection) => *stream = Some(connection),
+                        Err(e) => return Err(format!("Unable to connect IPFIX collector {}: {}", address, e))
+                    }
+                }
+                let result = match *stream {
+                    Some(ref mut connection) => connection.write_all(&message),
+                    None => unreachable!()
+                };
+                match result {
+                    Ok(_) => Ok(()),
+                    Err(e) => {
+                        // Templates are resent on the next connection.
+                        *stream = None;
+                        Err(format!("Unable to send IPFIX message: {}", e))
+                    }
+                }
+            }
+        }
+    }
+}
+
+
+struct MessageBuilder {
+    body: Vec<u8>,
+    set_id: Option<u16>,
+    set_start: usize,
+}
+
+impl MessageBuilder {
+    fn new() -> MessageBuilder {
+        MessageBuilder {
+            body: vec![0u8; MESSAGE_HEADER_LENGTH],
+            set_id: None,
+            set_start: 0
+        }
+    }
+
+    fn add_templates(&mut self) {
+        self.open_set(TEMPLATE_SET_ID);
+        for &(template_id, fields) in TEMPLATES.iter() {
+            push_u16(&mut self.body, template_id);
+            push_u16(&mut self.body, fields.len() as u16);
+            for &(element, length) in fields {
+                push_u16(&mut self.body, element);
+                push_u16(&mut self.body, length);
+            }
+        }
+        self.close_set();
+    }
+
+    fn fits(&self, template_id: u16, record: &[u8]) -> bool {
+        let set_overhead = if self.set_id == Some(template_id) { 0 } else { SET_HEADER_LENGTH };
+        self.body.len() + set_overhead + record.len() <= MAX_MESSAGE_LENGTH
+    }
+
+    fn add_record(&mut self, template_id: u16, record: Vec<u8>) {
+        if self.set_id != Some(template_id) {
+            self.close_set();
+            self.open_set(template_id);
+        }
+        self.body.extend(record);
+    }
+
+    fn open_set(&mut self, set_id: u16) {
+        self.set_id = Some(set_id);
+        self.set_start = self.body.len();
+        push_u16(&mut self.body, set_id);
+        push_u16(&mut self.body, 0u16);
+    }
+
+    fn close_set(&mut self) {
+        if self.set_id.is_some() {
+            let length = (self.body.len() - self.set_start) as u16;
+            write_u16(&mut self.body[self.set_start + 2..], length);
+            self.set_id = None;
+        }
+    }
+
+    fn finish(mut self, sequence: u32, observation_domain: u32) -> Vec<u8> {
+        self.close_set();
+        let length = self.body.len() as u16;
+        let mut header = Vec::with_capacity(MESSAGE_HEADER_LENGTH);
+        push_u16(&mut header, IPFIX_VERSION);
+        push_u16(&mut header, length);
+        push_u32(&mut header, (time_now() / 1000) as u32);
+        push_u32(&mut header, sequence);
+        push_u32(&mut header, observation_domain);
+        self.body[..MESSAGE_HEADER_LENGTH].copy_from_slice(&header);
+        self.body
+    }
+}
+
+
+fn encode_window_record(key: &FlowKey, stats: &Stats, start_time: u64, end_time: u64) -> Option<(u16, Vec<u8>)> {
+    let mut record = vec![];
+    let template_id = match push_addresses(&mut record, key.source, key.destination) {
+        Some(4) => WINDOW_IPV4_TEMPLATE,
+        Some(_) => WINDOW_IPV6_TEMPLATE,
+        None => return None
+    };
+    push_u16(&mut record, key.source_port.unwrap_or(0));
+    push_u16(&mut record, key.destination_port.unwrap_or(0));
+    record.push(key.protocol.number());
+    push_u16(&mut record, tcp_flag_bits(&key.attributes));
+    push_u64(&mut record, stats.count as u64);
+    push_u64(&mut record, stats.bytes);
+    record.push(stats.min_ttl);
+    record.push(stats.max_ttl);
+    push_u64(&mut record, start_time);
+    push_u64(&mut record, end_time);
+    Some((template_id, record))
+}
+
+
+/// A bidirectional flow is exported as two uniflow records, one per direction.
+fn encode_flow_records(flow: &Flow) -> Vec<(u16, Vec<u8>)> {
+    let mut records = vec![];
+    let (source_port, destination_port) = (flow.source_port.unwrap_or(0), flow.destination_port.unwrap_or(0));
+    let directions = [
+        (flow.source, source_port, flow.destination, destination_port,
+         flow.pkt_count, flow.size, flow.min_ttl, flow.max_ttl, INITIATOR),
+        (flow.destination, destination_port, flow.source, source_port,
+         flow.reverse_pkt_count, flow.reverse_size, flow.reverse_min_ttl, flow.reverse_max_ttl, REVERSE_INITIATOR),
+    ];
+    for &(src, src_port, dst, dst_port, packets, bytes, min_ttl, max_ttl, direction) in directions.iter() {
+        if packets == 0 {
+            continue;
+        }
+        let mut record = vec![];
+        let template_id = match push_addresses(&mut record, src, dst) {
+            Some(4) => FLOW_IPV4_TEMPLATE,
+            Some(_) => FLOW_IPV6_TEMPLATE,
+            None => continue
+        };
+        push_u16(&mut record, src_port);
+        push_u16(&mut record, dst_port);
+        record.push(flow.protocol.number());
+        push_u16(&mut record, tcp_flag_bits(&flow.attributes));
+        push_u64(&mut record, packets as u64);
+        push_u64(&mut record, bytes);
+        record.push(min_ttl);
+        record.push(max_ttl);
+        push_u64(&mut record, flow.start_time);
+        push_u64(&mut record, flow.end_time);
+        record.push(direction);
+        records.push((template_id, record));
+    }
+    records
+}
+
+
+/// Writes both addresses and returns their IP version, `None` when families differ.
+fn push_addresses(record: &mut Vec<u8>, source: IpAddr, destination: IpAddr) -> Option<u8> {
+    match (source, destination) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            record.extend_from_slice(&src.octets());
+            record.extend_from_slice(&dst.octets());
+            Some(4)
+        },
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            record.extend_from_slice(&src.octets());
+            record.extend_from_slice(&dst.octets());
+            Some(6)
+        },
+        _ => None
+    }
+}
+
+
+pub fn tcp_flag_bits(attributes: &[Attribute]) -> u16 {
+    attributes.iter()
+        .map(|attribute| {
+            match *attribute {
+                Attribute::TcpFlags(flags) => flags,
+                _ => 0u16
+            }
+        })
+        .fold(0u16, |bits, flags| { bits | flags })
+}
+
+
+pub fn push_u16(buffer: &mut Vec<u8>, value: u16) {
+    buffer.push((value >> 8) as u8);
+    buffer.push(value as u8);
+}
+
+
+pub fn push_u32(buffer: &mut Vec<u8>, value: u32) {
+    push_u16(buffer, (value >> 16) as u16);
+    push_u16(buffer, value as u16);
+}
+
+
+pub fn push_u64(buffer: &mut Vec<u8>, value: u64) {
+    push_u32(buffer, (value >> 32) as u32);
+    push_u32(buffer, value as u32);
+}
+
+
+fn write_u16(buffer: &mut [u8], value: u16) {
+    buffer[0] = (value >> 8) as u8;
+    buffer[1] = value as u8;
+}
+
+
+pub fn run_ipfix_exporter(receiver: Receiver<Window>, mut exporter: IpfixExporter) -> thread::JoinHandle<()> {
+
+    thread::spawn(move || {
+        loop {
+            match receiver.recv() {
+                Ok(window) => {
+                    if let Err(e) = exporter.export_window(&window) {
+                        error!("{}", e);
+                    }
+                },
+                Err(e) => {
+                    error!("IPFIX exporter receiver error occured: {}", e);
+                    drop(receiver);
+                    break;
+                }
+            }
+        }
+    })
+
+}
+
+
+pub fn run_ipfix_flow_exporter(receiver: Receiver<Vec<Flow>>, mut exporter: IpfixExporter) -> thread::JoinHandle<()> {
+
+    thread::spawn(move || {
+        loop {
+            match receiver.recv() {
+                Ok(flows) => {
+                    if let Err(e) = exporter.export_flows(&flows) {
+                        error!("{}", e);
+                    }
+                },
+                Err(e) => {
+                    error!("IPFIX exporter receiver error occured: {}", e);
+                    drop(receiver);
+                    break;
+                }
+            }
+        }
+    })
+
+}
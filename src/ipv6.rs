@@ -0,0 +1,151 @@
+use pnet::packet::ip::IpNextHeaderProtocol;
+
+use fragment::Fragment;
+
+
+const HOP_BY_HOP: u8 = 0;
+const ROUTING: u8 = 43;
+const FRAGMENT: u8 = 44;
+const AUTHENTICATION: u8 = 51;
+const DESTINATION_OPTIONS: u8 = 60;
+const MOBILITY: u8 = 135;
+const HOST_IDENTITY: u8 = 139;
+const SHIM6: u8 = 140;
+
+const FRAGMENT_HEADER_LENGTH: usize = 8;
+
+
+pub fn extension_name(extension: u8) -> Option<&'static str> {
+    match extension {
+        HOP_BY_HOP => Some("HopByHop"),
+        ROUTING => Some("Routing"),
+        FRAGMENT => Some("Fragment"),
+        AUTHENTICATION => Some("Authentication"),
+        DESTINATION_OPTIONS => Some("DestinationOptions"),
+        MOBILITY => Some("Mobility"),
+        HOST_IDENTITY => Some("HostIdentity"),
+        SHIM6 => Some("Shim6"),
+        _ => None
+    }
+}
+
+
+/// Follows the extension header chain starting with `next_header` to the
+/// upper-layer protocol and returns it with its payload, the extension
+/// headers passed, in order, and the fragment header if there is one.
+/// The walk stops after the fragment header of non-first fragments since
+/// the rest of the chain is in the first fragment. `None` when a header
+/// is truncated.
+pub fn walk_extension_headers(next_header: IpNextHeaderProtocol, payload: &[u8])
+                              -> Option<(IpNextHeaderProtocol, &[u8], Vec<u8>, Option<Fragment>)> {
+    let mut protocol = next_header;
+    let mut payload = payload;
+    let mut extensions = vec![];
+    let mut fragment = None;
+    loop {
+        let length = match protocol.0 {
+            HOP_BY_HOP | ROUTING | DESTINATION_OPTIONS | MOBILITY | HOST_IDENTITY | SHIM6 => {
+                // Length in 8 octet units, not counting the first 8 octets.
+                (*payload.get(1)? as usize + 1) * 8
+            },
+            AUTHENTICATION => {
+                // Length in 4 octet units, not counting the first 2 units.
+                (*payload.get(1)? as usize + 2) * 4
+            },
+            FRAGMENT => {
+                if payload.len() < FRAGMENT_HEADER_LENGTH {
+                    return None;
+                }
+                fragment = Some(Fragment {
+                    id: ((payload[4] as u32) << 24) | ((payload[5] as u32) << 16) | ((payload[6] as u32) << 8) | payload[7] as u32,
+                    offset: (((payload[2] as u16) << 8) | payload[3] as u16) >> 3
+                });
+                FRAGMENT_HEADER_LENGTH
+            },
+            _ => return Some((protocol, payload, extensions, fragment))
+        };
+        if payload.len() < length {
+            return None;
+        }
+        extensions.push(protocol.0);
+        protocol = IpNextHeaderProtocol(payload[0]);
+        payload = &payload[length..];
+        if fragment.map_or(false, |fragment| { !fragment.is_first() }) {
+            return Some((protocol, payload, extensions, fragment));
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use pnet::packet::ip::IpNextHeaderProtocol;
+
+    use super::{walk_extension_headers, AUTHENTICATION, DESTINATION_OPTIONS, FRAGMENT, HOP_BY_HOP, ROUTING};
+
+    const UDP: u8 = 17;
+
+    fn fragment_header(next_header: u8, offset: u16, more: bool, id: u32) -> Vec<u8> {
+        let offset = (offset << 3) | if more { 1 } else { 0 };
+        vec![next_header, 0, (offset >> 8) as u8, offset as u8, (id >> 24) as u8, (id >> 16) as u8, (id >> 8) as u8, id as u8]
+    }
+
+    #[test]
+    fn returns_payload_without_extension_headers() {
+        let payload = [0xaa; 8];
+        let (protocol, rest, extensions, fragment) = walk_extension_headers(IpNextHeaderProtocol(UDP), &payload).unwrap();
+        assert_eq!(protocol, IpNextHeaderProtocol(UDP));
+        assert_eq!(rest, &payload[..]);
+        assert!(extensions.is_empty());
+        assert!(fragment.is_none());
+    }
+
+    #[test]
+    fn walks_headers_of_both_length_units() {
+        // Hop-by-hop options of 16 octets, routing of 8 and authentication of (1 + 2) * 4.
+        let mut payload = vec![ROUTING, 1];
+        payload.extend_from_slice(&[0; 14]);
+        payload.extend_from_slice(&[AUTHENTICATION, 0, 0, 0, 0, 0, 0, 0]);
+        payload.extend_from_slice(&[UDP, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        payload.extend_from_slice(&[0xaa; 4]);
+        let (protocol, rest, extensions, fragment) = walk_extension_headers(IpNextHeaderProtocol(HOP_BY_HOP), &payload).unwrap();
+        assert_eq!(protocol, IpNextHeaderProtocol(UDP));
+        assert_eq!(rest, &[0xaa; 4][..]);
+        assert_eq!(extensions, vec![HOP_BY_HOP, ROUTING, AUTHENTICATION]);
+        assert!(fragment.is_none());
+    }
+
+    #[test]
+    fn rejects_truncated_headers() {
+        assert!(walk_extension_headers(IpNextHeaderProtocol(HOP_BY_HOP), &[UDP]).is_none());
+        assert!(walk_extension_headers(IpNextHeaderProtocol(DESTINATION_OPTIONS), &[UDP, 1, 0, 0, 0, 0, 0, 0]).is_none());
+        assert!(walk_extension_headers(IpNextHeaderProtocol(AUTHENTICATION), &[UDP, 1, 0, 0, 0, 0, 0, 0]).is_none());
+        assert!(walk_extension_headers(IpNextHeaderProtocol(FRAGMENT), &[UDP, 0, 0, 0, 0, 0, 0]).is_none());
+    }
+
+    #[test]
+    fn walks_on_after_first_fragment() {
+        let mut payload = fragment_header(DESTINATION_OPTIONS, 0, true, 0x01020304);
+        payload.extend_from_slice(&[UDP, 0, 1, 4, 0, 0, 0, 0]);
+        payload.extend_from_slice(&[0xaa; 8]);
+        let (protocol, rest, extensions, fragment) = walk_extension_headers(IpNextHeaderProtocol(FRAGMENT), &payload).unwrap();
+        assert_eq!(protocol, IpNextHeaderProtocol(UDP));
+        assert_eq!(rest, &[0xaa; 8][..]);
+        assert_eq!(extensions, vec![FRAGMENT, DESTINATION_OPTIONS]);
+        let fragment = fragment.unwrap();
+        assert_eq!((fragment.id, fragment.offset), (0x01020304, 0));
+    }
+
+    #[test]
+    fn stops_after_fragment_header_of_non_first_fragment() {
+        // The payload after the fragment header continues the datagram, it is not a header.
+        let mut payload = fragment_header(DESTINATION_OPTIONS, 185, false, 7);
+        payload.extend_from_slice(&[0xff; 3]);
+        let (protocol, rest, extensions, fragment) = walk_extension_headers(IpNextHeaderProtocol(FRAGMENT), &payload).unwrap();
+        assert_eq!(protocol, IpNextHeaderProtocol(DESTINATION_OPTIONS));
+        assert_eq!(rest, &[0xff; 3][..]);
+        assert_eq!(extensions, vec![FRAGMENT]);
+        let fragment = fragment.unwrap();
+        assert_eq!((fragment.id, fragment.offset), (7, 185));
+    }
+}
@@ -6,81 +6,296 @@ extern crate lazy_static;
 extern crate env_logger;
 extern crate argparse;
 extern crate time;
-#[macro_use]
 extern crate influx_db_client;
 extern crate spmc;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate toml;
+extern crate libc;
 
+mod bpf;
 mod collector;
+mod config;
+mod flow_collector;
+mod flow_key;
+mod fragment;
+mod ipfix;
+mod ipv6;
+mod metrics;
+mod monitor;
+mod netflow;
+mod pcap;
 mod probe;
+mod rollup;
+mod rules;
+mod sink;
+mod socket;
 mod store;
+mod ttl;
+mod tunnel;
 
-use std::sync::mpsc::{channel};
-
-use argparse::{ArgumentParser, Collect, StoreTrue, Store};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
 
-use collector::{run_collector, SimpleIpfix, Window};
-use probe::run_probe;
-use store::run_storer;
+use collector::{run_collector, run_offline_collector, Sample, Window, WindowCollector};
+use config::Config;
+use flow_collector::{run_flow_collector, run_offline_flow_collector, Flow};
+use ipfix::{run_ipfix_exporter, run_ipfix_flow_exporter, IpfixExporter};
+use metrics::{run_metrics_server, Metrics, MetricsSink};
+use monitor::run_monitor;
+use netflow::{run_netflow_exporter, NetflowExporter};
+use pcap::Rotation;
+use probe::{run_file_probe, run_probe, Capture};
+use rollup::{length_name, run_rollup};
+use rules::Rules;
+use sink::{create_sink, run_flow_sink, run_sink, Destination, FanoutSink, Sink};
 
-struct Config {
-    verbose: bool,
-    interfaces: Vec<String>,
-    sampling: u32,
-    influx_host: String,
-    influx_db: String,
-    cfg_file: String,
-    processors: u8,
+lazy_static! {
+    static ref CONFIG: Config = config::load_or_exit();
 }
 
 
-lazy_static! {
-    static ref CONFIG: Config = {
-    
-        let mut cfg = Config {
-            verbose: false,
-            interfaces: vec!["lo".to_string()],
-            sampling: 1u32,
-            influx_host: "http://localhost:8086".to_string(),
-            influx_db: "mydb".to_string(),
-            cfg_file: "/etc/ttl_test/config.json".to_string(),
-            processors: 2,
-        };
-        {
-            let mut ap = ArgumentParser::new();
-            ap.set_description("Packet capturer and aggregator");
-            ap.refer(&mut cfg.verbose)
-                .add_option(&["-v", "--verbose"], StoreTrue, "Enable verbose mode");
-            ap.refer(&mut cfg.interfaces)
-                .add_argument("INTERFACES", Collect, "Capturing interfaces");
-            ap.refer(&mut cfg.sampling)
-                .add_option(&["-s", "--sampling"], Store, "How much packets are not captured");
-            ap.refer(&mut cfg.influx_host)
-                .add_option(&["-i", "--influx-host"], Store, "Influx host address");
-            ap.refer(&mut cfg.influx_db)
-                .add_option(&["-d", "--database"], Store, "Influx database name");
-            ap.refer(&mut cfg.cfg_file)
-                .add_option(&["-c", "--config"], Store, "Config file path");
-            ap.refer(&mut cfg.processors)
-                .add_option(&["-w", "--workers"], Store, "Specifies how many processors should run");
-            ap.parse_args_or_exit();
+/// Hands a copy of every received item to each of the outputs.
+fn run_fanout<T: Clone + Send + 'static>(receiver: Receiver<T>, senders: Vec<Sender<T>>) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        loop {
+            match receiver.recv() {
+                Ok(item) => {
+                    for sender in senders.iter() {
+                        if let Err(e) = sender.send(item.clone()) {
+                            error!("Fanout send error occured: {}", e);
+                        }
+                    }
+                },
+                Err(e) => {
+                    error!("Fanout receiver error occured: {}", e);
+                    drop(receiver);
+                    break;
+                }
+            }
         }
-        cfg
-    };
+    })
 }
 
 
 fn main() {
     env_logger::init().unwrap();
     info!("Starting packet capag");
-    let (tx, rx) = channel::<SimpleIpfix>();
-    let (window_tx, window_rx) = channel::<Window>();
+    let (tx, rx) = channel::<Sample>();
     let sampling = CONFIG.sampling;
-    let interfaces: Vec<String> = CONFIG.interfaces.clone().into_iter()
-        .map(|iface| {iface.to_string()})
-        .collect::<Vec<String>>();
-    let guard_vec = run_probe(tx, interfaces, sampling, CONFIG.processors);
-    run_collector(rx, window_tx, sampling);
-    run_storer(window_rx);
+    let offline = !CONFIG.read_file.is_empty();
+    let rules = match Rules::parse(&CONFIG.rules) {
+        Ok(rules) => Arc::new(rules),
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+    let mut guard_vec = if offline {
+        let filter = if CONFIG.filter.is_empty() { None } else { Some(CONFIG.filter.clone()) };
+        match run_file_probe(tx, CONFIG.read_file.clone(), sampling, filter, rules, CONFIG.decapsulate) {
+            Ok(guards) => guards,
+            Err(e) => {
+                error!("{}", e);
+                return;
+            }
+        }
+    } else {
+        let dump = if CONFIG.dump_dir.is_empty() {
+            None
+        } else {
+            Some(Rotation {
+                directory: CONFIG.dump_dir.clone(),
+                max_size: CONFIG.dump_max_size * 1024 * 1024,
+                max_age: CONFIG.dump_interval * 1000,
+                retention: CONFIG.dump_files
+            })
+        };
+        let captures: Vec<Capture> = CONFIG.interfaces.iter()
+            .map(|iface| {
+                let settings = CONFIG.interface(iface);
+                // An empty filter of the interface turns the global one off.
+                let filter = settings.filter.unwrap_or_else(|| { CONFIG.filter.clone() });
+                Capture {
+                    interface: iface.to_string(),
+                    processors: settings.processors.unwrap_or(CONFIG.processors),
+                    dump: if settings.dump.unwrap_or(true) { dump.clone() } else { None },
+                    filter: if filter.is_empty() { None } else { Some(filter) }
+                }
+            })
+            .collect();
+        match run_probe(tx, captures, sampling, rules, CONFIG.decapsulate) {
+            Ok(guards) => guards,
+            Err(e) => {
+                error!("{}", e);
+                return;
+            }
+        }
+    };
+    let ipfix_exporter = if CONFIG.ipfix_target.is_empty() {
+        None
+    } else {
+        match IpfixExporter::new(CONFIG.ipfix_target.as_str(), CONFIG.ipfix_transport.as_str(), CONFIG.ipfix_domain) {
+            Ok(exporter) => Some(exporter),
+            Err(e) => {
+                error!("{}", e);
+                return;
+            }
+        }
+    };
+    let netflow_exporter = if CONFIG.netflow_target.is_empty() {
+        None
+    } else {
+        match NetflowExporter::new(CONFIG.netflow_target.as_str(), CONFIG.netflow_version, CONFIG.netflow_source_id, sampling) {
+            Ok(exporter) => Some(exporter),
+            Err(e) => {
+                error!("{}", e);
+                return;
+            }
+        }
+    };
+    let metrics = if CONFIG.metrics_listen.is_empty() {
+        None
+    } else {
+        let metrics = Arc::new(Mutex::new(Metrics::new()));
+        if let Err(e) = run_metrics_server(CONFIG.metrics_listen.as_str(), metrics.clone()) {
+            error!("{}", e);
+            return;
+        }
+        Some(metrics)
+    };
+    if CONFIG.monitor_interval > 0 {
+        let mut sinks: Vec<Box<dyn Sink>> = vec![];
+        if !CONFIG.sinks.is_empty() {
+            // Counters are totals, a lost report is made up by the next one,
+            // so they stay out of the journal the windows spill to.
+            let mut cfg = CONFIG.clone();
+            cfg.influx_journal = "".to_string();
+            match create_sink(&cfg, &Destination::base()) {
+                Ok(sink) => sinks.push(sink),
+                Err(e) => {
+                    error!("{}", e);
+                    return;
+                }
+            }
+        }
+        if !sinks.is_empty() {
+            run_monitor(Box::new(FanoutSink::new(sinks)), CONFIG.monitor_interval * 1000);
+        }
+    }
+    match CONFIG.mode.as_str() {
+        "flows" => {
+            if netflow_exporter.is_some() {
+                warn!("NetFlow export is only available in `windows` mode");
+            }
+            if !CONFIG.rollups.is_empty() {
+                warn!("Rollups are only available in `windows` mode");
+            }
+            let (flow_tx, flow_rx) = channel::<Vec<Flow>>();
+            guard_vec.push(if offline {
+                run_offline_flow_collector(rx, flow_tx, sampling, CONFIG.idle_timeout * 1000, CONFIG.active_timeout * 1000)
+            } else {
+                run_flow_collector(rx, flow_tx, sampling, CONFIG.idle_timeout * 1000, CONFIG.active_timeout * 1000)
+            });
+            let mut senders = vec![];
+            if !CONFIG.sinks.is_empty() {
+                let sink = match create_sink(&CONFIG, &Destination::base()) {
+                    Ok(sink) => sink,
+                    Err(e) => {
+                        error!("{}", e);
+                        return;
+                    }
+                };
+                let (store_tx, store_rx) = channel::<Vec<Flow>>();
+                guard_vec.push(run_flow_sink(store_rx, sink));
+                senders.push(store_tx);
+            }
+            if let Some(exporter) = ipfix_exporter {
+                let (ipfix_tx, ipfix_rx) = channel::<Vec<Flow>>();
+                guard_vec.push(run_ipfix_flow_exporter(ipfix_rx, exporter));
+                senders.push(ipfix_tx);
+            }
+            if let Some(metrics) = metrics {
+                let (metrics_tx, metrics_rx) = channel::<Vec<Flow>>();
+                guard_vec.push(run_flow_sink(metrics_rx, Box::new(MetricsSink::new(metrics))));
+                senders.push(metrics_tx);
+            }
+            guard_vec.push(run_fanout(flow_rx, senders));
+        },
+        "windows" => {
+            let (window_tx, window_rx) = channel::<Window>();
+            guard_vec.push(if offline {
+                run_offline_collector(rx, window_tx, sampling, CONFIG.window_length * 1000)
+            } else {
+                let collector = Arc::new(Mutex::new(WindowCollector::new(sampling, window_tx)));
+                if let Some(ref metrics) = metrics {
+                    metrics.lock().unwrap().set_collector(collector.clone());
+                }
+                run_collector(rx, collector, CONFIG.window_length * 1000)
+            });
+            let mut senders = vec![];
+            if !CONFIG.sinks.is_empty() {
+                let sink = match create_sink(&CONFIG, &Destination::base()) {
+                    Ok(sink) => sink,
+                    Err(e) => {
+                        error!("{}", e);
+                        return;
+                    }
+                };
+                let (store_tx, store_rx) = channel::<Window>();
+                guard_vec.push(run_sink(store_rx, sink));
+                senders.push(store_tx);
+                for length in CONFIG.rollups.iter() {
+                    let name = length_name(*length);
+                    let destination = match CONFIG.rollup_storage.as_str() {
+                        "retention_policy" => Destination {
+                            suffix: "".to_string(),
+                            retention_policy: Some(format!("rollup_{}", name))
+                        },
+                        _ => Destination {
+                            suffix: format!("_{}", name),
+                            retention_policy: None
+                        }
+                    };
+                    let sink = match create_sink(&CONFIG, &destination) {
+                        Ok(sink) => sink,
+                        Err(e) => {
+                            error!("{}", e);
+                            return;
+                        }
+                    };
+                    let (rollup_tx, rollup_rx) = channel::<Window>();
+                    let (rollup_store_tx, rollup_store_rx) = channel::<Window>();
+                    guard_vec.push(run_rollup(rollup_rx, rollup_store_tx, *length * 1000));
+                    guard_vec.push(run_sink(rollup_store_rx, sink));
+                    senders.push(rollup_tx);
+                }
+            }
+            if let Some(exporter) = ipfix_exporter {
+                let (ipfix_tx, ipfix_rx) = channel::<Window>();
+                guard_vec.push(run_ipfix_exporter(ipfix_rx, exporter));
+                senders.push(ipfix_tx);
+            }
+            if let Some(exporter) = netflow_exporter {
+                let (netflow_tx, netflow_rx) = channel::<Window>();
+                guard_vec.push(run_netflow_exporter(netflow_rx, exporter));
+                senders.push(netflow_tx);
+            }
+            if let Some(metrics) = metrics {
+                let (metrics_tx, metrics_rx) = channel::<Window>();
+                guard_vec.push(run_sink(metrics_rx, Box::new(MetricsSink::new(metrics))));
+                senders.push(metrics_tx);
+            }
+            guard_vec.push(run_fanout(window_rx, senders));
+        },
+        mode => {
+            error!("Unknown aggregation mode: {}", mode);
+            return;
+        }
+    }
     for guard in guard_vec {
         guard.join().unwrap();
     }
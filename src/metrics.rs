@@ -0,0 +1,320 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use collector::{time_now, Window, WindowCollector};
+use flow_collector::Flow;
+use flow_key::{tcp_flag_names, Attribute, Protocol};
+use monitor::{self, Counters, InterfaceSnapshot};
+use sink::Sink;
+
+
+/// Milliseconds a client may take to send its request or read the response,
+/// requests are served one at a time.
+const REQUEST_TIMEOUT: u64 = 5000;
+
+
+/// Packet and byte counts of a window or flow batch broken down the ways
+/// Prometheus is asked for them.
+#[derive(Debug,Clone,Default)]
+struct Breakdown {
+    packets: BTreeMap<String, u64>,
+    bytes: BTreeMap<String, u64>,
+    tcp_flags: BTreeMap<String, u64>,
+    icmp_types: BTreeMap<(String, String), u64>,
+    fragments: u64,
+}
+
+impl Breakdown {
+    fn from_window(window: &Window) -> Breakdown {
+        let mut breakdown = Breakdown::default();
+        for (key, stats) in window.iter() {
+            breakdown.add(key.protocol, stats.count as u64, stats.bytes);
+            for attribute in key.attributes.iter() {
+                breakdown.add_attribute(key.protocol, attribute, stats.count as u64);
+            }
+            breakdown.fragments += stats.fragments as u64;
+        }
+        breakdown
+    }
+
+    fn add(&mut self, protocol: Protocol, packets: u64, bytes: u64) -> () {
+        *self.packets.entry(protocol.to_string()).or_insert(0) += packets;
+        *self.bytes.entry(protocol.to_string()).or_insert(0) += bytes;
+    }
+
+    /// `packets` are those that carried `attribute`, not all packets of the window key or flow.
+    fn add_attribute(&mut self, protocol: Protocol, attribute: &Attribute, packets: u64) -> () {
+        match *attribute {
+            Attribute::TcpFlags(flags) => *self.tcp_flags.entry(tcp_flag_names(flags)).or_insert(0) += packets,
+            Attribute::IcmpType(_) => {
+                *self.icmp_types.entry((protocol.to_string(), attribute.value(protocol))).or_insert(0) += packets
+            },
+            _ => ()
+        }
+    }
+
+    fn merge(&mut self, breakdown: &Breakdown) -> () {
+        for (key, count) in breakdown.packets.iter() {
+            *self.packets.entry(key.clone()).or_insert(0) += *count;
+        }
+        for (key, count) in breakdown.bytes.iter() {
+            *self.bytes.entry(key.clone()).or_insert(0) += *count;
+        }
+        for (key, count) in breakdown.tcp_flags.iter() {
+            *self.tcp_flags.entry(key.clone()).or_insert(0) += *count;
+        }
+        for (key, count) in breakdown.icmp_types.iter() {
+            *self.icmp_types.entry(key.clone()).or_insert(0) += *count;
+        }
+        self.fragments += breakdown.fragments;
+    }
+
+    fn render(&self, out: &mut String, prefix: &str, kind: &str) -> () {
+        let name = format!("{}packets{}", prefix, suffix(kind));
+        metric_header(out, &name, kind, "Packets by protocol, scaled by sampling");
+        for (protocol, count) in self.packets.iter() {
+            metric_line(out, &name, &[("proto", protocol)], *count);
+        }
+        let name = format!("{}bytes{}", prefix, suffix(kind));
+        metric_header(out, &name, kind, "Bytes by protocol, scaled by sampling");
+        for (protocol, count) in self.bytes.iter() {
+            metric_line(out, &name, &[("proto", protocol)], *count);
+        }
+        let name = format!("{}tcp_flags_packets{}", prefix, suffix(kind));
+        metric_header(out, &name, kind, "TCP packets by flag set");
+        for (flags, count) in self.tcp_flags.iter() {
+            metric_line(out, &name, &[("flags", flags)], *count);
+        }
+        let name = format!("{}icmp_packets{}", prefix, suffix(kind));
+        metric_header(out, &name, kind, "ICMP packets by type");
+        for (&(ref protocol, ref icmp_type), count) in self.icmp_types.iter() {
+            metric_line(out, &name, &[("proto", protocol), ("type", icmp_type)], *count);
+        }
+        let name = format!("{}fragment_packets{}", prefix, suffix(kind));
+        metric_header(out, &name, kind, "Packets that were IP fragments, scaled by sampling");
+        metric_line(out, &name, &[], self.fragments);
+    }
+}
+
+
+/// Totals since start, the breakdown of the last window and of the window being
+/// collected, shared between the sink feeding it and the HTTP server. Pipeline
+/// counters are read from the monitor on every request.
+pub struct Metrics {
+    started: u64,
+    totals: Breakdown,
+    last_window: Option<(u64, u64, Breakdown)>,
+    windows: u64,
+    flows: u64,
+    hop_changes: u64,
+    sources: usize,
+    collector: Option<Arc<Mutex<WindowCollector>>>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            started: time_now(),
+            totals: Breakdown::default(),
+            last_window: None,
+            windows: 0,
+            flows: 0,
+            hop_changes: 0,
+            sources: 0,
+            collector: None
+        }
+    }
+
+    pub fn add_window(&mut self, window: &Window) -> () {
+        let breakdown = Breakdown::from_window(window);
+        self.totals.merge(&breakdown);
+        self.last_window = Some((window.start_time(), window.end_time(), breakdown));
+        self.windows += 1;
+        self.hop_changes += window.events().len() as u64;
+        self.sources = window.sources().len();
+    }
+
+    pub fn add_flows(&mut self, flows: &[Flow]) -> () {
+        for flow in flows {
+            let packets = flow.pkt_count as u64 + flow.reverse_pkt_count as u64;
+            self.totals.add(flow.protocol, packets, flow.size + flow.reverse_size);
+            for (attribute, packets) in flow.attributes.iter().zip(flow.attribute_packets.iter()) {
+                self.totals.add_attribute(flow.protocol, attribute, *packets as u64);
+            }
+        }
+        self.flows += flows.len() as u64;
+    }
+
+    /// Live windows are taken from `collector` as they are being collected.
+    pub fn set_collector(&mut self, collector: Arc<Mutex<WindowCollector>>) -> () {
+        self.collector = Some(collector);
+    }
+
+    /// Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.totals.render(&mut out, "ttl_", "counter");
+        if let Some((start_time, end_time, ref breakdown)) = self.last_window {
+            breakdown.render(&mut out, "ttl_window_", "gauge");
+            metric_header(&mut out, "ttl_window_start_seconds", "gauge", "Start of the last window");
+            metric_line(&mut out, "ttl_window_start_seconds", &[], start_time / 1000);
+            metric_header(&mut out, "ttl_window_end_seconds", "gauge", "End of the last window");
+            metric_line(&mut out, "ttl_window_end_seconds", &[], end_time / 1000);
+            metric_header(&mut out, "ttl_window_sources", "gauge", "Sources with a known hop distance in the last window");
+            metric_line(&mut out, "ttl_window_sources", &[], self.sources as u64);
+        }
+        let current = self.collector.as_ref()
+            .and_then(|collector| { collector.lock().unwrap().current_window(time_now()) });
+        if let Some(window) = current {
+            Breakdown::from_window(&window).render(&mut out, "ttl_current_window_", "gauge");
+            metric_header(&mut out, "ttl_current_window_start_seconds", "gauge", "Start of the window being collected");
+            metric_line(&mut out, "ttl_current_window_start_seconds", &[], window.start_time() / 1000);
+            metric_header(&mut out, "ttl_current_window_sources", "gauge", "Sources seen so far in the window being collected");
+            metric_line(&mut out, "ttl_current_window_sources", &[], window.sources().len() as u64);
+        }
+        metric_header(&mut out, "ttl_windows_total", "counter", "Windows aggregated since start");
+        metric_line(&mut out, "ttl_windows_total", &[], self.windows);
+        metric_header(&mut out, "ttl_flows_total", "counter", "Flows exported since start");
+        metric_line(&mut out, "ttl_flows_total", &[], self.flows);
+        metric_header(&mut out, "ttl_hop_changes_total", "counter", "Hop distance changes since start");
+        metric_line(&mut out, "ttl_hop_changes_total", &[], self.hop_changes);
+        render_counters(&mut out, &monitor::snapshot());
+        metric_header(&mut out, "process_start_time_seconds", "gauge", "Start time of the probe");
+        metric_line(&mut out, "process_start_time_seconds", &[], self.started / 1000);
+        out
+    }
+}
+
+
+fn render_counters(out: &mut String, counters: &Counters) -> () {
+    let interface_metrics: [(&str, &str, &str, fn(&InterfaceSnapshot) -> u64); 10] = [
+        ("ttl_interface_received_total", "counter", "Frames received from the interface", |i| { i.received }),
+        ("ttl_interface_sampled_out_total", "counter", "Frames skipped by sampling", |i| { i.sampled_out }),
+        ("ttl_interface_parse_failed_total", "counter", "Frames that could not be parsed", |i| { i.parse_failed }),
+        ("ttl_interface_unsupported_ethertype_total", "counter", "Frames of other than IPv4 or IPv6 ethertypes", |i| { i.unsupported_ethertype }),
+        ("ttl_interface_send_failed_total", "counter", "Frames or samples lost to closed queues", |i| { i.send_failed }),
+        ("ttl_interface_unattributed_fragments_total", "counter", "Fragments dropped because the first fragment of their datagram was not seen", |i| { i.unattributed_fragments }),
+        ("ttl_interface_dump_dropped_total", "counter", "Sampled frames not dumped because the pcap writer fell behind", |i| { i.dump_dropped }),
+        ("ttl_interface_queue_depth", "gauge", "Frames waiting for a processor", |i| { i.queue_depth }),
+        ("ttl_interface_kernel_received_total", "counter", "Frames seen by the capture socket in the kernel", |i| { i.kernel_received }),
+        ("ttl_interface_kernel_dropped_total", "counter", "Frames dropped by the kernel before the capture socket was read", |i| { i.kernel_dropped }),
+    ];
+    for &(name, kind, help, value) in interface_metrics.iter() {
+        metric_header(out, name, kind, help);
+        for interface in counters.interfaces.iter() {
+            metric_line(out, name, &[("interface", &interface.interface)], value(interface));
+        }
+    }
+    metric_header(out, "ttl_sample_queue_depth", "gauge", "Samples waiting for the collector");
+    metric_line(out, "ttl_sample_queue_depth", &[], counters.sample_queue_depth);
+}
+
+
+fn suffix(kind: &str) -> &'static str {
+    if kind == "counter" { "_total" } else { "" }
+}
+
+
+fn metric_header(out: &mut String, name: &str, kind: &str, help: &str) -> () {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+
+fn metric_line(out: &mut String, name: &str, labels: &[(&str, &str)], value: u64) -> () {
+    if labels.is_empty() {
+        writeln!(out, "{} {}", name, value).unwrap();
+    } else {
+        let labels: Vec<String> = labels.iter()
+            .map(|&(label, value)| { format!("{}=\"{}\"", label, escape_label(value)) })
+            .collect();
+        writeln!(out, "{}{{{}}} {}", name, labels.join(","), value).unwrap();
+    }
+}
+
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+
+/// Feeds windows and flows into the shared metrics.
+pub struct MetricsSink {
+    metrics: Arc<Mutex<Metrics>>,
+}
+
+impl MetricsSink {
+    pub fn new(metrics: Arc<Mutex<Metrics>>) -> MetricsSink {
+        MetricsSink {
+            metrics: metrics
+        }
+    }
+}
+
+impl Sink for MetricsSink {
+    fn write_window(&mut self, window: &Window) -> Result<(), String> {
+        self.metrics.lock().unwrap().add_window(window);
+        Ok(())
+    }
+
+    fn write_flows(&mut self, flows: &[Flow]) -> Result<(), String> {
+        self.metrics.lock().unwrap().add_flows(flows);
+        Ok(())
+    }
+}
+
+
+/// Serves `GET /metrics` on `listen`. The server thread is not joined, it runs
+/// for as long as the probe does.
+pub fn run_metrics_server(listen: &str, metrics: Arc<Mutex<Metrics>>) -> Result<(), String> {
+    let listener = match TcpListener::bind(listen) {
+        Ok(listener) => listener,
+        Err(e) => return Err(format!("Unable to listen for metrics on {}: {}", listen, e))
+    };
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = handle_request(stream, &metrics) {
+                        warn!("Metrics request failed: {}", e);
+                    }
+                },
+                Err(e) => error!("Metrics listener error occured: {}", e)
+            }
+        }
+    });
+    Ok(())
+}
+
+
+fn handle_request(stream: TcpStream, metrics: &Arc<Mutex<Metrics>>) -> Result<(), String> {
+    let timeout = Some(Duration::from_millis(REQUEST_TIMEOUT));
+    stream.set_read_timeout(timeout).map_err(|e| { format!("{}", e) })?;
+    stream.set_write_timeout(timeout).map_err(|e| { format!("{}", e) })?;
+    let mut reader = BufReader::new(stream.try_clone().map_err(|e| { format!("{}", e) })?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).map_err(|e| { format!("{}", e) })?;
+    loop {
+        let mut header = String::new();
+        match reader.read_line(&mut header) {
+            Ok(0) => break,
+            Ok(_) if header.trim().is_empty() => break,
+            Ok(_) => (),
+            Err(e) => return Err(format!("{}", e))
+        }
+    }
+    let parts: Vec<&str> = request_line.split_whitespace().collect();
+    let (status, body) = match (parts.get(0), parts.get(1)) {
+        (Some(&"GET"), Some(&"/metrics")) => ("200 OK", metrics.lock().unwrap().render()),
+        (Some(&"GET"), _) => ("404 Not Found", "Not Found\n".to_string()),
+        _ => ("405 Method Not Allowed", "Method Not Allowed\n".to_string())
+    };
+    let mut stream = stream;
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+           status, body.len(), body).map_err(|e| { format!("{}", e) })
+}
@@ -0,0 +1,222 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use collector::time_now;
+use sink::Sink;
+use socket::StatisticsReader;
+
+
+lazy_static! {
+    static ref INTERFACES: Mutex<BTreeMap<String, Arc<InterfaceCounters>>> = Mutex::new(BTreeMap::new());
+}
+
+/// Samples sent by the processors that no collector has taken yet.
+static SAMPLE_QUEUE: AtomicUsize = AtomicUsize::new(0);
+
+
+/// Counters of a single capturing interface, shared by its sniffer and processors.
+/// Frames of transport protocols that are not aggregated are neither parse failures
+/// nor unsupported ethertypes, so the counters do not add up to `received`.
+#[derive(Debug,Default)]
+pub struct InterfaceCounters {
+    received: AtomicUsize,
+    sampled_out: AtomicUsize,
+    parse_failed: AtomicUsize,
+    unsupported_ethertype: AtomicUsize,
+    send_failed: AtomicUsize,
+    unattributed_fragments: AtomicUsize,
+    dump_dropped: AtomicUsize,
+    queued: AtomicUsize,
+    kernel_received: AtomicUsize,
+    kernel_dropped: AtomicUsize,
+    statistics: Mutex<Option<StatisticsReader>>,
+}
+
+impl InterfaceCounters {
+    pub fn received(&self) -> () {
+        self.received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn sampled_out(&self) -> () {
+        self.sampled_out.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn parse_failed(&self) -> () {
+        self.parse_failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn unsupported_ethertype(&self) -> () {
+        self.unsupported_ethertype.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn send_failed(&self) -> () {
+        self.send_failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A non-first fragment whose first fragment was not seen.
+    pub fn unattributed_fragment(&self) -> () {
+        self.unattributed_fragments.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A sampled frame was not dumped because the pcap writer fell behind.
+    pub fn dump_dropped(&self) -> () {
+        self.dump_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A frame entered the queue between the sniffer and the processors.
+    pub fn enqueued(&self) -> () {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dequeued(&self) -> () {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Capture socket whose statistics are read at window boundaries, `None` once it is closed.
+    pub fn set_statistics_reader(&self, reader: Option<StatisticsReader>) -> () {
+        *self.statistics.lock().unwrap() = reader;
+    }
+
+    fn kernel_statistics(&self, received: u64, dropped: u64) -> () {
+        self.kernel_received.fetch_add(received as usize, Ordering::Relaxed);
+        self.kernel_dropped.fetch_add(dropped as usize, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self, interface: &str) -> InterfaceSnapshot {
+        InterfaceSnapshot {
+            interface: interface.to_string(),
+            received: self.received.load(Ordering::Relaxed) as u64,
+            sampled_out: self.sampled_out.load(Ordering::Relaxed) as u64,
+            parse_failed: self.parse_failed.load(Ordering::Relaxed) as u64,
+            unsupported_ethertype: self.unsupported_ethertype.load(Ordering::Relaxed) as u64,
+            send_failed: self.send_failed.load(Ordering::Relaxed) as u64,
+            unattributed_fragments: self.unattributed_fragments.load(Ordering::Relaxed) as u64,
+            dump_dropped: self.dump_dropped.load(Ordering::Relaxed) as u64,
+            queue_depth: self.queued.load(Ordering::Relaxed) as u64,
+            kernel_received: self.kernel_received.load(Ordering::Relaxed) as u64,
+            kernel_dropped: self.kernel_dropped.load(Ordering::Relaxed) as u64
+        }
+    }
+}
+
+
+/// Counters of `interface`, created on first use.
+pub fn interface_counters(interface: &str) -> Arc<InterfaceCounters> {
+    INTERFACES.lock().unwrap()
+        .entry(interface.to_string())
+        .or_insert_with(|| { Arc::new(InterfaceCounters::default()) })
+        .clone()
+}
+
+
+/// Reads the statistics of every capture socket, which count from the previous
+/// read, and adds them to the totals. Interfaces without them, such as capture
+/// files, are left out.
+pub fn read_kernel_statistics() -> Vec<KernelStatistics> {
+    INTERFACES.lock().unwrap().iter()
+        .filter_map(|(interface, counters)| {
+            let statistics = match *counters.statistics.lock().unwrap() {
+                Some(ref reader) => reader.read(),
+                None => return None
+            };
+            match statistics {
+                Ok(Some((received, dropped))) => {
+                    counters.kernel_statistics(received, dropped);
+                    if dropped > 0 {
+                        warn!("Kernel dropped {} of {} frames on {} in the last window", dropped, received, interface);
+                    }
+                    Some(KernelStatistics {
+                        interface: interface.clone(),
+                        received: received,
+                        dropped: dropped
+                    })
+                },
+                Ok(None) => None,
+                Err(e) => {
+                    warn!("{}: {}", interface, e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+
+pub fn sample_sent() -> () {
+    SAMPLE_QUEUE.fetch_add(1, Ordering::Relaxed);
+}
+
+
+pub fn sample_received() -> () {
+    SAMPLE_QUEUE.fetch_sub(1, Ordering::Relaxed);
+}
+
+
+/// Takes back `sample_sent` for a sample the queue did not accept. Samples are
+/// counted before they are sent so that the collector never takes one off first.
+pub fn sample_dropped() -> () {
+    SAMPLE_QUEUE.fetch_sub(1, Ordering::Relaxed);
+}
+
+
+#[derive(Debug,Clone)]
+pub struct InterfaceSnapshot {
+    pub interface: String,
+    pub received: u64,
+    pub sampled_out: u64,
+    pub parse_failed: u64,
+    pub unsupported_ethertype: u64,
+    pub send_failed: u64,
+    pub unattributed_fragments: u64,
+    pub dump_dropped: u64,
+    pub queue_depth: u64,
+    pub kernel_received: u64,
+    pub kernel_dropped: u64,
+}
+
+
+/// Frames seen and dropped by the capture socket of `interface` during a window.
+#[derive(Debug,Clone)]
+pub struct KernelStatistics {
+    pub interface: String,
+    pub received: u64,
+    pub dropped: u64,
+}
+
+
+/// Counters are totals since start, queue depths are current.
+#[derive(Debug,Clone)]
+pub struct Counters {
+    pub timestamp: u64,
+    pub interfaces: Vec<InterfaceSnapshot>,
+    pub sample_queue_depth: u64,
+}
+
+
+pub fn snapshot() -> Counters {
+    let interfaces = INTERFACES.lock().unwrap().iter()
+        .map(|(interface, counters)| { counters.snapshot(interface) })
+        .collect();
+    Counters {
+        timestamp: time_now(),
+        interfaces: interfaces,
+        sample_queue_depth: SAMPLE_QUEUE.load(Ordering::Relaxed) as u64
+    }
+}
+
+
+/// Hands a snapshot of the counters to `sink` every `interval` milliseconds.
+/// The thread is not joined, it runs for as long as the probe does.
+pub fn run_monitor(mut sink: Box<dyn Sink>, interval: u64) -> () {
+    thread::spawn(move || {
+        loop {
+            thread::sleep(Duration::from_millis(interval));
+            if let Err(e) = sink.write_counters(&snapshot()) {
+                error!("{}", e);
+            }
+        }
+    });
+}
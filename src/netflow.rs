@@ -0,0 +1,442 @@
+use std::cmp;
+use std::net::{IpAddr, ToSocketAddrs, UdpSocket, SocketAddr};
+use std::sync::mpsc::Receiver;
+use std::thread;
+
+use collector::{Stats, Window};
+use flow_key::FlowKey;
+use ipfix::{push_u16, push_u32, push_u64, tcp_flag_bits};
+
+
+const V5_HEADER_LENGTH: usize = 24;
+const V5_RECORD_LENGTH: usize = 48;
+/// NetFlow v5 collectors reject datagrams with more than 30 records.
+const V5_MAX_RECORDS: usize = 30;
+/// Two top bits of the v5 sampling field, `01` stands for deterministic packet interval.
+const V5_SAMPLING_MODE: u16 = 0x4000;
+const V5_SAMPLING_INTERVAL_MASK: u16 = 0x3fff;
+
+const V9_HEADER_LENGTH: usize = 20;
+const FLOWSET_HEADER_LENGTH: usize = 4;
+const TEMPLATE_FLOWSET_ID: u16 = 0;
+const OPTIONS_TEMPLATE_FLOWSET_ID: u16 = 1;
+/// Keeps a single datagram inside a common ethernet MTU.
+const MAX_MESSAGE_LENGTH: usize = 1400;
+
+// Field types, see RFC 3954 section 8
+const IN_BYTES: u16 = 1;
+const IN_PKTS: u16 = 2;
+const PROTOCOL: u16 = 4;
+const TCP_FLAGS: u16 = 6;
+const L4_SRC_PORT: u16 = 7;
+const IPV4_SRC_ADDR: u16 = 8;
+const L4_DST_PORT: u16 = 11;
+const IPV4_DST_ADDR: u16 = 12;
+const LAST_SWITCHED: u16 = 21;
+const FIRST_SWITCHED: u16 = 22;
+const IPV6_SRC_ADDR: u16 = 27;
+const IPV6_DST_ADDR: u16 = 28;
+const SAMPLING_INTERVAL: u16 = 34;
+const SAMPLING_ALGORITHM: u16 = 35;
+const MIN_TTL: u16 = 52;
+const MAX_TTL: u16 = 53;
+// Scope field types of options templates
+const SCOPE_SYSTEM: u16 = 1;
+
+const DETERMINISTIC_SAMPLING: u8 = 1;
+
+const WINDOW_IPV4_TEMPLATE: u16 = 256;
+const WINDOW_IPV6_TEMPLATE: u16 = 257;
+const SAMPLING_OPTIONS_TEMPLATE: u16 = 258;
+
+static WINDOW_IPV4_FIELDS: [(u16, u16); 12] = [
+    (IPV4_SRC_ADDR, 4),
+    (IPV4_DST_ADDR, 4),
+    (L4_SRC_PORT, 2),
+    (L4_DST_PORT, 2),
+    (PROTOCOL, 1),
+    (TCP_FLAGS, 1),
+    (IN_PKTS, 4),
+    (IN_BYTES, 8),
+    (MIN_TTL, 1),
+    (MAX_TTL, 1),
+    (FIRST_SWITCHED, 4),
+    (LAST_SWITCHED, 4),
+];
+
+static WINDOW_IPV6_FIELDS: [(u16, u16); 12] = [
+    (IPV6_SRC_ADDR, 16),
+    (IPV6_DST_ADDR, 16),
+    (L4_SRC_PORT, 2),
+    (L4_DST_PORT, 2),
+    (PROTOCOL, 1),
+    (TCP_FLAGS, 1),
+    (IN_PKTS, 4),
+    (IN_BYTES, 8),
+    (MIN_TTL, 1),
+    (MAX_TTL, 1),
+    (FIRST_SWITCHED, 4),
+    (LAST_SWITCHED, 4),
+];
+
+static TEMPLATES: [(u16, &'static [(u16, u16)]); 2] = [
+    (WINDOW_IPV4_TEMPLATE, &WINDOW_IPV4_FIELDS),
+    (WINDOW_IPV6_TEMPLATE, &WINDOW_IPV6_FIELDS),
+];
+
+static SAMPLING_SCOPE_FIELDS: [(u16, u16); 1] = [
+    (SCOPE_SYSTEM, 4),
+];
+
+static SAMPLING_OPTION_FIELDS: [(u16, u16); 2] = [
+    (SAMPLING_INTERVAL, 4),
+    (SAMPLING_ALGORITHM, 1),
+];
+
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum Version {
+    V5,
+    V9,
+}
+
+
+pub struct NetflowExporter {
+    socket: UdpSocket,
+    address: SocketAddr,
+    version: Version,
+    source_id: u32,
+    sampling: u32,
+    sequence: u32,
+    /// Start of the first exported window, so that uptimes follow the capture
+    /// timestamps when reading files as well.
+    boot_time: Option<u64>,
+}
+
+impl NetflowExporter {
+    pub fn new(target: &str, version: u8, source_id: u32, sampling: u32) -> Result<NetflowExporter, String> {
+        let version = match version {
+            5 => Version::V5,
+            9 => Version::V9,
+            _ => return Err(format!("Unsupported NetFlow version: {}", version))
+        };
+        if version == Version::V5 && sampling > V5_SAMPLING_INTERVAL_MASK as u32 {
+            return Err(format!("Sampling interval {} does not fit a NetFlow v5 header", sampling));
+        }
+        let address = match target.to_socket_addrs() {
+            Ok(mut addresses) => match addresses.next() {
+                Some(address) => address,
+                None => return Err(format!("Unable to resolve NetFlow collector {}", target))
+            },
+            Err(e) => return Err(format!("Unable to resolve NetFlow collector {}: {}", target, e))
+        };
+        let bind_address = if address.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = match UdpSocket::bind(bind_address) {
+            Ok(socket) => socket,
+            Err(e) => return Err(format!("Unable to open NetFlow socket: {}", e))
+        };
+        Ok(NetflowExporter {
+            socket: socket,
+            address: address,
+            version: version,
+            source_id: source_id,
+            sampling: if sampling < 2u32 { 1u32 } else { sampling },
+            sequence: 0u32,
+            boot_time: None
+        })
+    }
+
+    pub fn export_window(&mut self, window: &Window) -> Result<(), String> {
+        if self.boot_time.is_none() {
+            self.boot_time = Some(window.start_time());
+        }
+        match self.version {
+            Version::V5 => self.export_v5(window),
+            Version::V9 => self.export_v9(window)
+        }
+    }
+
+    /// Window counters are already scaled by the sampling rate, NetFlow
+    /// carries the observed counts and leaves scaling to the collector.
+    fn observed_packets(&self, stats: &Stats) -> u32 {
+        cmp::max(stats.count / self.sampling, 1u32)
+    }
+
+    fn observed_bytes(&self, stats: &Stats) -> u64 {
+        stats.bytes / self.sampling as u64
+    }
+
+    /// Milliseconds since the first window started, NetFlow's notion of system uptime.
+    fn uptime(&self, timestamp: u64) -> u32 {
+        timestamp.saturating_sub(self.boot_time.unwrap_or(timestamp)) as u32
+    }
+
+    fn export_v5(&mut self, window: &Window) -> Result<(), String> {
+        let first_switched = self.uptime(window.start_time());
+        let last_switched = self.uptime(window.end_time());
+        let records: Vec<Vec<u8>> = window.iter()
+            .filter_map(|(key, stats)| {
+                encode_v5_record(key, self.observed_packets(stats), self.observed_bytes(stats), first_switched, last_switched)
+            })
+            .collect();
+        for chunk in records.chunks(V5_MAX_RECORDS) {
+            let mut message = Vec::with_capacity(V5_HEADER_LENGTH + chunk.len() * V5_RECORD_LENGTH);
+            self.push_v5_header(&mut message, chunk.len() as u16, window.end_time());
+            for record in chunk {
+                message.extend_from_slice(record);
+            }
+            self.send_message(&message)?;
+            self.sequence = self.sequence.wrapping_add(chunk.len() as u32);
+        }
+        Ok(())
+    }
+
+    /// Windows are exported when they end, which is also the export time in capture files.
+    fn push_v5_header(&self, message: &mut Vec<u8>, count: u16, export_time: u64) {
+        push_u16(message, 5u16);
+        push_u16(message, count);
+        push_u32(message, self.uptime(export_time));
+        push_u32(message, (export_time / 1000) as u32);
+        push_u32(message, ((export_time % 1000) * 1000000) as u32);
+        push_u32(message, self.sequence);
+        message.push(0u8);
+        message.push(self.source_id as u8);
+        let sampling = if self.sampling < 2u32 {
+            0u16
+        } else {
+            V5_SAMPLING_MODE | (self.sampling as u16 & V5_SAMPLING_INTERVAL_MASK)
+        };
+        push_u16(message, sampling);
+    }
+
+    fn export_v9(&mut self, window: &Window) -> Result<(), String> {
+        let first_switched = self.uptime(window.start_time());
+        let last_switched = self.uptime(window.end_time());
+        let mut records: Vec<(u16, Vec<u8>)> = window.iter()
+            .filter_map(|(key, stats)| {
+                encode_v9_record(key, self.observed_packets(stats), self.observed_bytes(stats), stats, first_switched, last_switched)
+            })
+            .collect();
+        records.sort_by_key(|&(template_id, _)| { template_id });
+        let export_time = window.end_time();
+        let uptime = self.uptime(export_time);
+        let mut message = self.new_v9_message();
+        for (template_id, record) in records {
+            if !message.fits(template_id, &record) {
+                let finished = message.finish(self.sequence, self.source_id, uptime, export_time);
+                self.send_message(&finished)?;
+                self.sequence = self.sequence.wrapping_add(1);
+                message = self.new_v9_message();
+            }
+            message.add_record(template_id, record);
+        }
+        let finished = message.finish(self.sequence, self.source_id, uptime, export_time);
+        self.send_message(&finished)?;
+        self.sequence = self.sequence.wrapping_add(1);
+        Ok(())
+    }
+
+    /// Templates and the sampling options ride along in every datagram since
+    /// UDP collectors may have missed earlier ones.
+    fn new_v9_message(&self) -> V9MessageBuilder {
+        let mut message = V9MessageBuilder::new();
+        message.add_templates();
+        message.add_sampling_options(self.source_id, self.sampling);
+        message
+    }
+
+    fn send_message(&self, message: &[u8]) -> Result<(), String> {
+        match self.socket.send_to(message, self.address) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Unable to send NetFlow datagram: {}", e))
+        }
+    }
+}
+
+
+struct V9MessageBuilder {
+    body: Vec<u8>,
+    count: u16,
+    flowset_id: Option<u16>,
+    flowset_start: usize,
+}
+
+impl V9MessageBuilder {
+    fn new() -> V9MessageBuilder {
+        V9MessageBuilder {
+            body: vec![0u8; V9_HEADER_LENGTH],
+            count: 0u16,
+            flowset_id: None,
+            flowset_start: 0
+        }
+    }
+
+    fn add_templates(&mut self) {
+        self.open_flowset(TEMPLATE_FLOWSET_ID);
+        for &(template_id, fields) in TEMPLATES.iter() {
+            push_u16(&mut self.body, template_id);
+            push_u16(&mut self.body, fields.len() as u16);
+            for &(field_type, length) in fields {
+                push_u16(&mut self.body, field_type);
+                push_u16(&mut self.body, length);
+            }
+            self.count += 1;
+        }
+        self.close_flowset();
+    }
+
+    fn add_sampling_options(&mut self, source_id: u32, sampling: u32) {
+        self.open_flowset(OPTIONS_TEMPLATE_FLOWSET_ID);
+        push_u16(&mut self.body, SAMPLING_OPTIONS_TEMPLATE);
+        push_u16(&mut self.body, (SAMPLING_SCOPE_FIELDS.len() * 4) as u16);
+        push_u16(&mut self.body, (SAMPLING_OPTION_FIELDS.len() * 4) as u16);
+        for &(field_type, length) in SAMPLING_SCOPE_FIELDS.iter().chain(SAMPLING_OPTION_FIELDS.iter()) {
+            push_u16(&mut self.body, field_type);
+            push_u16(&mut self.body, length);
+        }
+        self.count += 1;
+        self.close_flowset();
+
+        let mut record = vec![];
+        push_u32(&mut record, source_id);
+        push_u32(&mut record, sampling);
+        record.push(DETERMINISTIC_SAMPLING);
+        self.add_record(SAMPLING_OPTIONS_TEMPLATE, record);
+        self.close_flowset();
+    }
+
+    fn fits(&self, template_id: u16, record: &[u8]) -> bool {
+        let flowset_overhead = if self.flowset_id == Some(template_id) { 0 } else { FLOWSET_HEADER_LENGTH };
+        // Leaves room for padding the last flowset.
+        self.body.len() + flowset_overhead + record.len() + 3 <= MAX_MESSAGE_LENGTH
+    }
+
+    fn add_record(&mut self, template_id: u16, record: Vec<u8>) {
+        if self.flowset_id != Some(template_id) {
+            self.close_flowset();
+            self.open_flowset(template_id);
+        }
+        self.body.extend(record);
+        self.count += 1;
+    }
+
+    fn open_flowset(&mut self, flowset_id: u16) {
+        self.flowset_id = Some(flowset_id);
+        self.flowset_start = self.body.len();
+        push_u16(&mut self.body, flowset_id);
+        push_u16(&mut self.body, 0u16);
+    }
+
+    /// Pads the flowset to a 32 bit boundary and fills in its length.
+    fn close_flowset(&mut self) {
+        if self.flowset_id.is_some() {
+            while (self.body.len() - self.flowset_start) % 4 != 0 {
+                self.body.push(0u8);
+            }
+            let length = (self.body.len() - self.flowset_start) as u16;
+            write_u16(&mut self.body[self.flowset_start + 2..], length);
+            self.flowset_id = None;
+        }
+    }
+
+    fn finish(mut self, sequence: u32, source_id: u32, uptime: u32, export_time: u64) -> Vec<u8> {
+        self.close_flowset();
+        let mut header = Vec::with_capacity(V9_HEADER_LENGTH);
+        push_u16(&mut header, 9u16);
+        push_u16(&mut header, self.count);
+        push_u32(&mut header, uptime);
+        push_u32(&mut header, (export_time / 1000) as u32);
+        push_u32(&mut header, sequence);
+        push_u32(&mut header, source_id);
+        self.body[..V9_HEADER_LENGTH].copy_from_slice(&header);
+        self.body
+    }
+}
+
+
+/// NetFlow v5 only knows IPv4, other records are skipped.
+fn encode_v5_record(key: &FlowKey, packets: u32, bytes: u64, first_switched: u32, last_switched: u32) -> Option<Vec<u8>> {
+    let (source, destination) = match (key.source, key.destination) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => (src, dst),
+        _ => return None
+    };
+    let mut record = Vec::with_capacity(V5_RECORD_LENGTH);
+    record.extend_from_slice(&source.octets());
+    record.extend_from_slice(&destination.octets());
+    push_u32(&mut record, 0u32);
+    push_u16(&mut record, 0u16);
+    push_u16(&mut record, 0u16);
+    push_u32(&mut record, packets);
+    push_u32(&mut record, cmp::min(bytes, u32::max_value() as u64) as u32);
+    push_u32(&mut record, first_switched);
+    push_u32(&mut record, last_switched);
+    push_u16(&mut record, key.source_port.unwrap_or(0));
+    push_u16(&mut record, key.destination_port.unwrap_or(0));
+    record.push(0u8);
+    record.push(tcp_flag_bits(&key.attributes) as u8);
+    record.push(key.protocol.number());
+    record.push(0u8);
+    push_u16(&mut record, 0u16);
+    push_u16(&mut record, 0u16);
+    record.push(0u8);
+    record.push(0u8);
+    push_u16(&mut record, 0u16);
+    Some(record)
+}
+
+
+fn encode_v9_record(key: &FlowKey, packets: u32, bytes: u64, stats: &Stats, first_switched: u32, last_switched: u32) -> Option<(u16, Vec<u8>)> {
+    let mut record = vec![];
+    let template_id = match (key.source, key.destination) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            record.extend_from_slice(&src.octets());
+            record.extend_from_slice(&dst.octets());
+            WINDOW_IPV4_TEMPLATE
+        },
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            record.extend_from_slice(&src.octets());
+            record.extend_from_slice(&dst.octets());
+            WINDOW_IPV6_TEMPLATE
+        },
+        _ => return None
+    };
+    push_u16(&mut record, key.source_port.unwrap_or(0));
+    push_u16(&mut record, key.destination_port.unwrap_or(0));
+    record.push(key.protocol.number());
+    record.push(tcp_flag_bits(&key.attributes) as u8);
+    push_u32(&mut record, packets);
+    push_u64(&mut record, bytes);
+    record.push(stats.min_ttl);
+    record.push(stats.max_ttl);
+    push_u32(&mut record, first_switched);
+    push_u32(&mut record, last_switched);
+    Some((template_id, record))
+}
+
+
+fn write_u16(buffer: &mut [u8], value: u16) {
+    buffer[0] = (value >> 8) as u8;
+    buffer[1] = value as u8;
+}
+
+
+pub fn run_netflow_exporter(receiver: Receiver<Window>, mut exporter: NetflowExporter) -> thread::JoinHandle<()> {
+
+    thread::spawn(move || {
+        loop {
+            match receiver.recv() {
+                Ok(window) => {
+                    if let Err(e) = exporter.export_window(&window) {
+                        error!("{}", e);
+                    }
+                },
+                Err(e) => {
+                    error!("NetFlow exporter receiver error occured: {}", e);
+                    drop(receiver);
+                    break;
+                }
+            }
+        }
+    })
+
+}
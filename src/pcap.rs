@@ -0,0 +1,499 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
+use std::thread;
+
+use ipfix::{push_u16, push_u32};
+
+
+const PCAP_MAGIC_MICROS: u32 = 0xa1b2c3d4;
+const PCAP_MAGIC_NANOS: u32 = 0xa1b23c4d;
+const PCAP_HEADER_LENGTH: usize = 24;
+const PCAP_RECORD_HEADER_LENGTH: usize = 16;
+
+const PCAPNG_SECTION_HEADER: u32 = 0x0a0d0d0a;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 0x00000001;
+const PCAPNG_ENHANCED_PACKET: u32 = 0x00000006;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b3c4d;
+const PCAPNG_OPTION_END: u16 = 0;
+const PCAPNG_OPTION_TSRESOL: u16 = 9;
+
+const LINKTYPE_ETHERNET: u32 = 1;
+/// Largest snapshot length libpcap accepts, records are never longer. Dumps
+/// are written with it, frames are cut at the capture buffer size before.
+const MAX_SNAPLEN: u32 = 262144;
+/// Blocks are read whole, larger ones are taken for a corrupt file.
+const PCAPNG_MAX_BLOCK_LENGTH: usize = 16 * 1024 * 1024;
+
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum Format {
+    Pcap,
+    Pcapng,
+}
+
+
+/// Link type and timestamp resolution (units per second) of a pcapng interface.
+#[derive(Debug,Clone,Copy)]
+struct Interface {
+    link_type: u32,
+    units_per_second: u64,
+}
+
+
+/// Reads ethernet frames and their capture timestamps from pcap and pcapng files.
+pub struct PcapReader {
+    reader: BufReader<File>,
+    format: Format,
+    big_endian: bool,
+    /// Classic pcap timestamps are either in micro or nanoseconds.
+    nanos: bool,
+    /// Longest record of a classic pcap file.
+    snaplen: u32,
+    interfaces: Vec<Interface>,
+}
+
+impl PcapReader {
+    pub fn open(path: &str) -> Result<PcapReader, String> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) => return Err(format!("Unable to open capture file {}: {}", path, e))
+        };
+        let mut reader = PcapReader {
+            reader: BufReader::new(file),
+            format: Format::Pcap,
+            big_endian: false,
+            nanos: false,
+            snaplen: MAX_SNAPLEN,
+            interfaces: vec![]
+        };
+        let mut magic = [0u8; 4];
+        if let Err(e) = reader.reader.read_exact(&mut magic) {
+            return Err(format!("Unable to read capture file {}: {}", path, e));
+        }
+        match (read_u32(&magic, false), read_u32(&magic, true)) {
+            (PCAPNG_SECTION_HEADER, _) => {
+                reader.format = Format::Pcapng;
+                reader.read_section_header()?;
+            },
+            (PCAP_MAGIC_MICROS, _) | (_, PCAP_MAGIC_MICROS) => {
+                reader.big_endian = read_u32(&magic, true) == PCAP_MAGIC_MICROS;
+                reader.read_pcap_header()?;
+            },
+            (PCAP_MAGIC_NANOS, _) | (_, PCAP_MAGIC_NANOS) => {
+                reader.big_endian = read_u32(&magic, true) == PCAP_MAGIC_NANOS;
+                reader.nanos = true;
+                reader.read_pcap_header()?;
+            },
+            _ => return Err(format!("{} is neither a pcap nor a pcapng file", path))
+        }
+        Ok(reader)
+    }
+
+    /// Returns the next frame with its timestamp in milliseconds, `None` at the end of file.
+    pub fn next_packet(&mut self) -> Result<Option<(u64, Vec<u8>)>, String> {
+        match self.format {
+            Format::Pcap => self.next_pcap_packet(),
+            Format::Pcapng => self.next_pcapng_packet()
+        }
+    }
+
+    fn read_pcap_header(&mut self) -> Result<(), String> {
+        let header = self.read_bytes(PCAP_HEADER_LENGTH - 4)?;
+        let snaplen = read_u32(&header[12..], self.big_endian);
+        let link_type = read_u32(&header[16..], self.big_endian);
+        if link_type != LINKTYPE_ETHERNET {
+            return Err(format!("Unsupported capture link type: {}", link_type));
+        }
+        // Some writers leave the snapshot length unset.
+        if snaplen > 0 {
+            self.snaplen = cmp::min(snaplen, MAX_SNAPLEN);
+        }
+        Ok(())
+    }
+
+    fn next_pcap_packet(&mut self) -> Result<Option<(u64, Vec<u8>)>, String> {
+        let header = match self.read_bytes_or_eof(PCAP_RECORD_HEADER_LENGTH)? {
+            Some(header) => header,
+            None => return Ok(None)
+        };
+        let seconds = read_u32(&header, self.big_endian) as u64;
+        let fraction = read_u32(&header[4..], self.big_endian) as u64;
+        let captured_length = read_u32(&header[8..], self.big_endian);
+        if captured_length > self.snaplen {
+            return Err(format!("Invalid pcap record length: {}", captured_length));
+        }
+        let data = self.read_bytes(captured_length as usize)?;
+        let millis = if self.nanos { fraction / 1000000 } else { fraction / 1000 };
+        Ok(Some((seconds * 1000 + millis, data)))
+    }
+
+    /// The 4 byte block type was already consumed while detecting the format.
+    fn read_section_header(&mut self) -> Result<(), String> {
+        let lengths = self.read_bytes(8)?;
+        self.big_endian = match (read_u32(&lengths[4..], false), read_u32(&lengths[4..], true)) {
+            (PCAPNG_BYTE_ORDER_MAGIC, _) => false,
+            (_, PCAPNG_BYTE_ORDER_MAGIC) => true,
+            _ => return Err("Invalid pcapng byte order magic".to_string())
+        };
+        let block_length = read_u32(&lengths, self.big_endian) as usize;
+        if block_length < 12 + 4 || block_length > PCAPNG_MAX_BLOCK_LENGTH {
+            return Err(format!("Invalid pcapng section header length: {}", block_length));
+        }
+        self.read_bytes(block_length - 12)?;
+        // Interface ids are only unique within a section.
+        self.interfaces.clear();
+        Ok(())
+    }
+
+    fn next_pcapng_packet(&mut self) -> Result<Option<(u64, Vec<u8>)>, String> {
+        loop {
+            let block_type = match self.read_bytes_or_eof(4)? {
+                Some(block_type) => block_type,
+                None => return Ok(None)
+            };
+            if read_u32(&block_type, false) == PCAPNG_SECTION_HEADER {
+                self.read_section_header()?;
+                continue;
+            }
+            let block_type = read_u32(&block_type, self.big_endian);
+            let block_length = read_u32(&self.read_bytes(4)?, self.big_endian) as usize;
+            if block_length < 12 || block_length % 4 != 0 || block_length > PCAPNG_MAX_BLOCK_LENGTH {
+                return Err(format!("Invalid pcapng block length: {}", block_length));
+            }
+            let body = self.read_bytes(block_length - 8)?;
+            let body = &body[..block_length - 12];
+            match block_type {
+                PCAPNG_INTERFACE_DESCRIPTION => {
+                    let interface = self.parse_interface(body)?;
+                    self.interfaces.push(interface);
+                },
+                PCAPNG_ENHANCED_PACKET => {
+                    if body.len() < 20 {
+                        return Err("Truncated pcapng enhanced packet block".to_string());
+                    }
+                    let interface_id = read_u32(body, self.big_endian) as usize;
+                    let interface = match self.interfaces.get(interface_id) {
+                        Some(interface) => *interface,
+                        None => return Err(format!("Unknown pcapng interface: {}", interface_id))
+                    };
+                    if interface.link_type != LINKTYPE_ETHERNET {
+                        continue;
+                    }
+                    let timestamp = ((read_u32(&body[4..], self.big_endian) as u64) << 32)
+                        | read_u32(&body[8..], self.big_endian) as u64;
+                    let captured_length = read_u32(&body[12..], self.big_endian) as usize;
+                    if body.len() < 20 + captured_length {
+                        return Err("Truncated pcapng enhanced packet block".to_string());
+                    }
+                    let millis = to_millis(timestamp, interface.units_per_second);
+                    let data = body[20..20 + captured_length].to_vec();
+                    return Ok(Some((millis, data)));
+                },
+                // Simple packet blocks carry no timestamp, the rest carries no packets.
+                _ => {}
+            }
+        }
+    }
+
+    fn parse_interface(&self, body: &[u8]) -> Result<Interface, String> {
+        if body.len() < 8 {
+            return Err("Truncated pcapng interface description block".to_string());
+        }
+        let mut interface = Interface {
+            link_type: read_u16(body, self.big_endian) as u32,
+            units_per_second: 1000000
+        };
+        let mut options = &body[8..];
+        while options.len() >= 4 {
+            let code = read_u16(options, self.big_endian);
+            let length = read_u16(&options[2..], self.big_endian) as usize;
+            if code == PCAPNG_OPTION_END || options.len() < 4 + length {
+                break;
+            }
+            if code == PCAPNG_OPTION_TSRESOL && length >= 1 {
+                let resolution = options[4];
+                // The highest bit selects a power of two instead of ten.
+                let (base, exponent) = if resolution & 0x80 == 0 { (10u64, resolution) } else { (2u64, resolution & 0x7f) };
+                interface.units_per_second = match base.checked_pow(exponent as u32) {
+                    Some(units) => units,
+                    None => return Err(format!("Unsupported pcapng timestamp resolution: {}", resolution))
+                };
+            }
+            let padded_length = (length + 3) / 4 * 4;
+            options = &options[(4 + padded_length).min(options.len())..];
+        }
+        Ok(interface)
+    }
+
+    fn read_bytes(&mut self, length: usize) -> Result<Vec<u8>, String> {
+        match self.read_bytes_or_eof(length)? {
+            Some(bytes) => Ok(bytes),
+            None => Err("Unexpected end of capture file".to_string())
+        }
+    }
+
+    fn read_bytes_or_eof(&mut self, length: usize) -> Result<Option<Vec<u8>>, String> {
+        let mut buffer = vec![0u8; length];
+        match self.reader.read_exact(&mut buffer) {
+            Ok(_) => Ok(Some(buffer)),
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(format!("Unable to read capture file: {}", e))
+        }
+    }
+}
+
+
+/// When a dump file is closed and how many of them are kept.
+#[derive(Debug,Clone)]
+pub struct Rotation {
+    pub directory: String,
+    /// Bytes, zero disables size based rotation.
+    pub max_size: u64,
+    /// Milliseconds, zero disables time based rotation.
+    pub max_age: u64,
+    pub retention: usize,
+}
+
+
+/// Writes frames to `<directory>/<prefix>-<timestamp>.pcap` files and removes
+/// the oldest ones once there are more than `retention` of them.
+pub struct RotatingPcapWriter {
+    rotation: Rotation,
+    prefix: String,
+    writer: Option<BufWriter<File>>,
+    size: u64,
+    opened_at: u64,
+    files: VecDeque<PathBuf>,
+}
+
+impl RotatingPcapWriter {
+    pub fn new(rotation: Rotation, prefix: &str) -> Result<RotatingPcapWriter, String> {
+        if let Err(e) = fs::create_dir_all(&rotation.directory) {
+            return Err(format!("Unable to create dump directory {}: {}", rotation.directory, e));
+        }
+        let files = existing_dumps(Path::new(&rotation.directory), prefix)?;
+        Ok(RotatingPcapWriter {
+            rotation: rotation,
+            prefix: prefix.to_string(),
+            writer: None,
+            size: 0u64,
+            opened_at: 0u64,
+            files: files
+        })
+    }
+
+    /// `length` is the length of the frame on the wire, `frame` may be cut.
+    pub fn write(&mut self, timestamp: u64, frame: &[u8], length: usize) -> Result<(), String> {
+        if self.needs_rotation(timestamp) {
+            self.rotate(timestamp)?;
+        }
+        let frame = &frame[..cmp::min(frame.len(), MAX_SNAPLEN as usize)];
+        let mut record = Vec::with_capacity(PCAP_RECORD_HEADER_LENGTH + frame.len());
+        push_u32(&mut record, (timestamp / 1000) as u32);
+        push_u32(&mut record, ((timestamp % 1000) * 1000) as u32);
+        push_u32(&mut record, frame.len() as u32);
+        push_u32(&mut record, cmp::max(length, frame.len()) as u32);
+        record.extend_from_slice(frame);
+        self.size += record.len() as u64;
+        match self.writer {
+            // Flushed right away so that the dump is complete if the probe is killed.
+            Some(ref mut writer) => writer.write_all(&record)
+                .and_then(|_| { writer.flush() })
+                .map_err(|e| { format!("Unable to write dump file: {}", e) }),
+            None => unreachable!()
+        }
+    }
+
+    fn needs_rotation(&self, timestamp: u64) -> bool {
+        self.writer.is_none()
+            || (self.rotation.max_size > 0 && self.size >= self.rotation.max_size)
+            || (self.rotation.max_age > 0 && timestamp >= self.opened_at + self.rotation.max_age)
+    }
+
+    fn rotate(&mut self, timestamp: u64) -> Result<(), String> {
+        if let Some(mut writer) = self.writer.take() {
+            if let Err(e) = writer.flush() {
+                error!("Unable to flush dump file: {}", e);
+            }
+        }
+        let path = Path::new(&self.rotation.directory).join(format!("{}-{}.pcap", self.prefix, timestamp));
+        let mut writer = match File::create(&path) {
+            Ok(file) => BufWriter::new(file),
+            Err(e) => return Err(format!("Unable to create dump file {}: {}", path.display(), e))
+        };
+        // Written big endian, readers detect the byte order from the magic.
+        let mut header = Vec::with_capacity(PCAP_HEADER_LENGTH);
+        push_u32(&mut header, PCAP_MAGIC_MICROS);
+        push_u16(&mut header, 2u16);
+        push_u16(&mut header, 4u16);
+        push_u32(&mut header, 0u32);
+        push_u32(&mut header, 0u32);
+        push_u32(&mut header, MAX_SNAPLEN);
+        push_u32(&mut header, LINKTYPE_ETHERNET);
+        if let Err(e) = writer.write_all(&header) {
+            return Err(format!("Unable to write dump file {}: {}", path.display(), e));
+        }
+        self.writer = Some(writer);
+        self.size = header.len() as u64;
+        self.opened_at = timestamp;
+        self.files.push_back(path);
+        // The file being written is never removed.
+        while self.files.len() > cmp::max(self.rotation.retention, 1) {
+            if let Some(oldest) = self.files.pop_front() {
+                if let Err(e) = fs::remove_file(&oldest) {
+                    error!("Unable to remove dump file {}: {}", oldest.display(), e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+
+/// Dumps left over by previous runs count towards the retention cap, oldest first.
+fn existing_dumps(directory: &Path, prefix: &str) -> Result<VecDeque<PathBuf>, String> {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(e) => return Err(format!("Unable to list dump directory {}: {}", directory.display(), e))
+    };
+    let start = format!("{}-", prefix);
+    let mut dumps: Vec<(u64, PathBuf)> = entries
+        .filter_map(|entry| { entry.ok() })
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            if !name.starts_with(&start) || !name.ends_with(".pcap") || name.len() < start.len() + 5 {
+                return None;
+            }
+            let timestamp = name[start.len()..name.len() - 5].parse::<u64>().ok()?;
+            Some((timestamp, entry.path()))
+        })
+        .collect();
+    dumps.sort();
+    Ok(dumps.into_iter().map(|(_, path)| { path }).collect())
+}
+
+
+pub fn run_pcap_writer(receiver: Receiver<(u64, Vec<u8>, usize)>, mut writer: RotatingPcapWriter) -> thread::JoinHandle<()> {
+
+    thread::spawn(move || {
+        loop {
+            match receiver.recv() {
+                Ok((timestamp, frame, length)) => {
+                    if let Err(e) = writer.write(timestamp, &frame, length) {
+                        error!("{}", e);
+                    }
+                },
+                Err(e) => {
+                    error!("Pcap writer receiver error occured: {}", e);
+                    drop(receiver);
+                    break;
+                }
+            }
+        }
+    })
+
+}
+
+
+fn to_millis(timestamp: u64, units_per_second: u64) -> u64 {
+    let seconds = timestamp / units_per_second;
+    let fraction = timestamp % units_per_second;
+    // Resolutions need not be a multiple of milliseconds, e.g. 2^10 units per second.
+    seconds * 1000 + (fraction as u128 * 1000 / units_per_second as u128) as u64
+}
+
+
+fn read_u16(buffer: &[u8], big_endian: bool) -> u16 {
+    if big_endian {
+        ((buffer[0] as u16) << 8) | buffer[1] as u16
+    } else {
+        ((buffer[1] as u16) << 8) | buffer[0] as u16
+    }
+}
+
+
+fn read_u32(buffer: &[u8], big_endian: bool) -> u32 {
+    if big_endian {
+        ((read_u16(buffer, true) as u32) << 16) | read_u16(&buffer[2..], true) as u32
+    } else {
+        ((read_u16(&buffer[2..], false) as u32) << 16) | read_u16(buffer, false) as u32
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use super::{PcapReader, RotatingPcapWriter, Rotation};
+
+    fn read_all(name: &str) -> Result<Vec<(u64, Vec<u8>)>, String> {
+        let path = format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+        let mut reader = PcapReader::open(&path)?;
+        let mut packets = vec![];
+        while let Some(packet) = reader.next_packet()? {
+            packets.push(packet);
+        }
+        Ok(packets)
+    }
+
+    fn timestamps(packets: &[(u64, Vec<u8>)]) -> Vec<u64> {
+        packets.iter().map(|&(timestamp, _)| { timestamp }).collect()
+    }
+
+    #[test]
+    fn reads_little_endian_microsecond_pcap() {
+        let packets = read_all("micros-le.pcap").unwrap();
+        assert_eq!(timestamps(&packets), vec![1500, 2000]);
+        assert_eq!(packets[0].1, (0u8..60).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn reads_big_endian_nanosecond_pcap() {
+        let packets = read_all("nanos-be.pcap").unwrap();
+        assert_eq!(timestamps(&packets), vec![3999]);
+    }
+
+    #[test]
+    fn rejects_record_longer_than_snaplen() {
+        assert!(read_all("oversized-record.pcap").is_err());
+    }
+
+    #[test]
+    fn converts_pcapng_timestamp_resolutions() {
+        // Microseconds by default, then 2^10, 2^20 and 10^9 units per second.
+        let packets = read_all("tsresol.pcapng").unwrap();
+        assert_eq!(timestamps(&packets), vec![4250, 5500, 7750, 9999]);
+        assert_eq!(packets[3].1.len(), 60);
+    }
+
+    #[test]
+    fn rejects_oversized_pcapng_block() {
+        assert!(read_all("oversized-block.pcapng").is_err());
+    }
+
+    #[test]
+    fn reads_back_written_dump() {
+        let directory = env::temp_dir().join(format!("ttl-dump-{}", process::id()));
+        let rotation = Rotation {
+            directory: directory.to_string_lossy().into_owned(),
+            max_size: 0,
+            max_age: 0,
+            retention: 1
+        };
+        let mut writer = RotatingPcapWriter::new(rotation, "lo").unwrap();
+        let frame: Vec<u8> = (0..65536).map(|i| { i as u8 }).collect();
+        writer.write(1500, &frame, 65550).unwrap();
+        let path = writer.files.back().unwrap().to_string_lossy().into_owned();
+        let mut reader = PcapReader::open(&path).unwrap();
+        let packet = reader.next_packet();
+        fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(packet.unwrap(), Some((1500, frame)));
+    }
+}
@@ -1,123 +1,207 @@
 use std::net::IpAddr;
-use std::sync::mpsc::Sender;
-use std::collections::HashMap;
+use std::sync::mpsc::{sync_channel, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
 use std::thread;
 
-use pnet::datalink::{self};
 use pnet::packet::Packet;
 use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
 use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
-use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::ipv4::{Ipv4Flags, Ipv4Packet};
 use pnet::packet::ipv6::Ipv6Packet;
-use pnet::packet::tcp::{TcpPacket, TcpFlags};
+use pnet::packet::tcp::TcpPacket;
 use pnet::packet::udp::UdpPacket;
-use pnet::packet::icmp::{IcmpTypes, IcmpPacket, IcmpType};
-use pnet::datalink::Channel::Ethernet;
+use pnet::packet::vlan::VlanPacket;
+use pnet::packet::icmp::IcmpPacket;
+use pnet::packet::icmpv6::Icmpv6Packet;
 
 
 use spmc;
 
-use collector::SimpleIpfix;
-
-lazy_static! {
-    static ref ICMP_CONVERT: HashMap<IcmpType, &'static str> = {
-        let mut map = HashMap::new();
-        map.insert(IcmpTypes::EchoReply, "EchoReply");
-        map.insert(IcmpTypes::DestinationUnreachable, "DestinationUnreachable");
-        map.insert(IcmpTypes::SourceQuench, "SourceQuench");
-        map.insert(IcmpTypes::RedirectMessage, "RedirectMessage");
-        map.insert(IcmpTypes::EchoRequest, "EchoRequest");
-        map.insert(IcmpTypes::RouterAdvertisement, "RouterAdvertisement");
-        map.insert(IcmpTypes::RouterSolicitation, "RouterSolicitation");
-        map.insert(IcmpTypes::TimeExceeded, "TimeExceeded");
-        map.insert(IcmpTypes::ParameterProblem, "ParameterProblem");
-        map.insert(IcmpTypes::Timestamp, "Timestamp");
-        map.insert(IcmpTypes::TimestampReply, "TimestampReply");
-        map.insert(IcmpTypes::InformationRequest, "InformationRequest");
-        map.insert(IcmpTypes::InformationReply, "InformationReply");
-        map.insert(IcmpTypes::AddressMaskRequest, "AddressMaskRequest");
-        map.insert(IcmpTypes::AddressMaskReply, "AddressMaskReply");
-        map.insert(IcmpTypes::Traceroute, "Traceroute");
-        map
-    };
-}
+use bpf::BpfProgram;
+use collector::{time_now, Sample};
+use flow_key::{Attribute, FlowKey, Protocol, TCP_FLAG_MASK};
+use fragment::{Fragment, FragmentTable};
+use ipv6::walk_extension_headers;
+use monitor::{self, interface_counters, InterfaceCounters};
+use pcap::{run_pcap_writer, PcapReader, RotatingPcapWriter, Rotation};
+use rules::Rules;
+use socket::FrameReceiver;
+use tunnel::{self, Inner, TunnelType};
+
+const IPV6_HEADER_LENGTH: u32 = 40;
+/// Frames waiting for the pcap writer before further ones are dropped.
+const DUMP_QUEUE_LENGTH: usize = 4096;
 
 
 struct Probe {
-    sender: Sender<SimpleIpfix>,
+    sender: Sender<Sample>,
+    counters: Arc<InterfaceCounters>,
+    rules: Arc<Rules>,
+    decapsulate: bool,
+    fragments: Arc<Mutex<FragmentTable>>,
 }
 
 impl Probe {
-    pub fn new(sender: Sender<SimpleIpfix>) -> Probe {
+    pub fn new(sender: Sender<Sample>,
+               counters: Arc<InterfaceCounters>,
+               rules: Arc<Rules>,
+               decapsulate: bool,
+               fragments: Arc<Mutex<FragmentTable>>) -> Probe {
         Probe {
-            sender: sender
+            sender: sender,
+            counters: counters,
+            rules: rules,
+            decapsulate: decapsulate,
+            fragments: fragments
+        }
+    }
+
+    fn handle_packet(&self, ethernet: &EthernetPacket, timestamp: u64) {
+        if let Some(sample) = self.handle_frame(ethernet, timestamp, self.decapsulate) {
+            monitor::sample_sent();
+            match self.sender.send(sample) {
+                Err(e) => {
+                    monitor::sample_dropped();
+                    self.counters.send_failed();
+                    error!("Failed to send ipfix, due to: {}", e)
+                },
+                _ => {}
+            }
         }
     }
 
-    fn handle_packet(&self, ethernet: &EthernetPacket) {
-        let ipfix = match ethernet.get_ethertype() {
-            EtherTypes::Ipv4 => self.handle_ipv4_packet(ethernet),
-            EtherTypes::Ipv6 => self.handle_ipv6_packet(ethernet),
+    /// Tunnels are only decapsulated when `decapsulate` is set, inner packets are
+    /// not decapsulated again.
+    fn handle_frame(&self, ethernet: &EthernetPacket, timestamp: u64, decapsulate: bool) -> Option<Sample> {
+        let mut ethertype = ethernet.get_ethertype();
+        let mut payload = ethernet.payload();
+        let mut vlans = vec![];
+        // 802.1ad service tags come before 802.1Q customer tags, any number is unwrapped.
+        while ethertype == EtherTypes::Vlan || ethertype == EtherTypes::PBridge || ethertype == EtherTypes::QinQ {
+            match VlanPacket::new(payload) {
+                Some(vlan) => {
+                    vlans.push(vlan.get_vlan_identifier());
+                    ethertype = vlan.get_ethertype();
+                },
+                None => {
+                    self.counters.parse_failed();
+                    return None;
+                }
+            }
+            payload = &payload[VlanPacket::minimum_packet_size()..];
+        }
+
+        let sample = match ethertype {
+            EtherTypes::Ipv4 => self.handle_ipv4_packet(payload, timestamp, decapsulate),
+            EtherTypes::Ipv6 => self.handle_ipv6_packet(payload, timestamp, decapsulate),
             _ => {
+                self.counters.unsupported_ethertype();
                 None
             }
         };
+        sample.map(|mut sample| {
+            if !vlans.is_empty() {
+                // Frames inside a tunnel may be tagged too, their IDs follow the outer ones.
+                let inner = sample.key.attributes.iter_mut().find(|attribute| {
+                    match **attribute {
+                        Attribute::Vlans(_) => true,
+                        _ => false
+                    }
+                });
+                match inner {
+                    Some(&mut Attribute::Vlans(ref mut inner)) => {
+                        vlans.extend(inner.iter().cloned());
+                        *inner = vlans;
+                    },
+                    _ => sample.key.attributes.push(Attribute::Vlans(vlans))
+                }
+            }
+            sample
+        })
+    }
 
-        if let Some(ipfix) = ipfix {
-            match self.sender.send(ipfix) {
-                Err(e) => error!("Failed to send ipfix, due to: {}", e),
-                _ => {}
+    /// Sample of the inner packet, tagged with the tunnel it came through.
+    fn handle_tunnel(&self, tunnel: TunnelType, id: Option<u32>, inner: Inner, packet: &[u8], timestamp: u64) -> Option<Sample> {
+        let sample = match inner {
+            Inner::Ethernet => match EthernetPacket::new(packet) {
+                Some(ethernet) => self.handle_frame(&ethernet, timestamp, false),
+                None => {
+                    self.counters.parse_failed();
+                    None
+                }
+            },
+            Inner::Ipv4 => self.handle_ipv4_packet(packet, timestamp, false),
+            Inner::Ipv6 => self.handle_ipv6_packet(packet, timestamp, false)
+        };
+        sample.map(|mut sample| {
+            sample.key.attributes.push(Attribute::Tunnel(tunnel));
+            if let Some(id) = id {
+                sample.key.attributes.push(Attribute::TunnelId(id));
             }
-        }
+            sample
+        })
     }
 
-    fn handle_udp_packet(&self, source: IpAddr, destination: IpAddr, packet: &[u8]) -> Option<SimpleIpfix> {
+    fn handle_udp_packet(&self, source: IpAddr, destination: IpAddr, packet: &[u8]) -> Option<FlowKey> {
         if let Some(udp) = UdpPacket::new(packet) {
-            Some(
-                (
-                    create_address(source, udp.get_source()),
-                    create_address(destination, udp.get_destination()),
-                    "UDP",
-                    vec![]
-                )
-                )
+            Some(FlowKey {
+                source: source,
+                destination: destination,
+                source_port: Some(udp.get_source()),
+                destination_port: Some(udp.get_destination()),
+                protocol: Protocol::Udp,
+                attributes: vec![]
+            })
         } else {
+            self.counters.parse_failed();
             None
         }
     }
 
-    fn handle_tcp_packet(&self, source: IpAddr, destination: IpAddr, packet: &[u8]) -> Option<SimpleIpfix> {
+    fn handle_tcp_packet(&self, source: IpAddr, destination: IpAddr, packet: &[u8]) -> Option<FlowKey> {
         if let Some(tcp) = TcpPacket::new(packet) {
-            let flags = parse_flags(tcp.get_flags());
-            Some(
-                (
-                    create_address(source, tcp.get_source()),
-                    create_address(destination, tcp.get_destination()),
-                    "TCP",
-                    vec![("flags", flags)]
-                )
-                )
+            Some(FlowKey {
+                source: source,
+                destination: destination,
+                source_port: Some(tcp.get_source()),
+                destination_port: Some(tcp.get_destination()),
+                protocol: Protocol::Tcp,
+                attributes: vec![Attribute::TcpFlags(tcp.get_flags() & TCP_FLAG_MASK)]
+            })
         } else {
+            self.counters.parse_failed();
             None
         }
     }
 
-    fn handle_icmp_packet(&self, source: IpAddr, destination: IpAddr, packet: &[u8]) -> Option<SimpleIpfix> {
+    fn handle_icmp_packet(&self, source: IpAddr, destination: IpAddr, packet: &[u8]) -> Option<FlowKey> {
         if let Some(icmp) = IcmpPacket::new(packet) {
-            let icmp_type = icmp.get_icmp_type();
-            let type_name: String = match ICMP_CONVERT.get(&icmp_type) {
-                Some(name) => name.to_string(),
-                None => format!("{}", icmp.get_icmp_type().0)
-            };
-            Some(
-                (
-                    format!("{}", source),
-                    format!("{}", destination),
-                    "ICMP",
-                    vec![("type", type_name), ("code", format!("{}", icmp.get_icmp_code().0))]
-                )
-                )
+            Some(FlowKey {
+                source: source,
+                destination: destination,
+                source_port: None,
+                destination_port: None,
+                protocol: Protocol::Icmp,
+                attributes: vec![Attribute::IcmpType(icmp.get_icmp_type().0), Attribute::IcmpCode(icmp.get_icmp_code().0)]
+            })
         } else {
+            self.counters.parse_failed();
+            None
+        }
+    }
+
+    fn handle_icmpv6_packet(&self, source: IpAddr, destination: IpAddr, packet: &[u8]) -> Option<FlowKey> {
+        if let Some(icmp) = Icmpv6Packet::new(packet) {
+            Some(FlowKey {
+                source: source,
+                destination: destination,
+                source_port: None,
+                destination_port: None,
+                protocol: Protocol::Icmpv6,
+                attributes: vec![Attribute::IcmpType(icmp.get_icmpv6_type().0), Attribute::IcmpCode(icmp.get_icmpv6_code().0)]
+            })
+        } else {
+            self.counters.parse_failed();
             None
         }
     }
@@ -126,8 +210,8 @@ impl Probe {
                                  source: IpAddr,
                                  destination: IpAddr,
                                  protocol: IpNextHeaderProtocol,
-                                 packet: &[u8]) -> Option<SimpleIpfix> {
-        match protocol {
+                                 packet: &[u8]) -> Option<FlowKey> {
+        let key = match protocol {
             IpNextHeaderProtocols::Udp => {
                 self.handle_udp_packet(source, destination, packet)
             }
@@ -138,163 +222,353 @@ impl Probe {
                 self.handle_icmp_packet(source, destination, packet)
             }
             IpNextHeaderProtocols::Icmpv6 => {
-                Some((format!("{}", source), format!("{}", destination), "ICMPv6", vec![]))
+                self.handle_icmpv6_packet(source, destination, packet)
             }
             _ => {
                 None
             }
 
+        };
+        key.and_then(|mut key| {
+            self.rules.evaluate(&key).map(|labels| {
+                if !labels.is_empty() {
+                    key.attributes.push(Attribute::Tag(labels.join(",")));
+                }
+                key
+            })
+        })
+    }
+
+    /// Non-first fragments carry no transport header, they get the key of the first
+    /// fragment of their datagram. `attributes` are added to keys of first fragments
+    /// and unfragmented packets.
+    fn handle_ip_payload(&self,
+                         source: IpAddr,
+                         destination: IpAddr,
+                         protocol: IpNextHeaderProtocol,
+                         packet: &[u8],
+                         fragment: Option<Fragment>,
+                         attributes: Vec<Attribute>,
+                         timestamp: u64) -> Option<FlowKey> {
+        let fragment = match fragment {
+            Some(fragment) => fragment,
+            None => {
+                return self.handle_transport_protocol(source, destination, protocol, packet)
+                    .map(|mut key| {
+                        key.attributes.extend(attributes);
+                        key
+                    });
+            }
+        };
+        let datagram_protocol = if source.is_ipv4() { Some(protocol.0) } else { None };
+        let datagram = (source, destination, datagram_protocol, fragment.id);
+        if fragment.is_first() {
+            self.handle_transport_protocol(source, destination, protocol, packet)
+                .map(|mut key| {
+                    key.attributes.extend(attributes);
+                    self.fragments.lock().unwrap().insert(datagram, key.clone(), timestamp);
+                    key
+                })
+        } else {
+            let key = self.fragments.lock().unwrap().attribute(&datagram, timestamp);
+            if key.is_none() {
+                self.counters.unattributed_fragment();
+            }
+            key
         }
     }
 
-    fn handle_ipv4_packet(&self, ethernet: &EthernetPacket) -> Option<SimpleIpfix> {
-        let header = Ipv4Packet::new(ethernet.payload());
+    fn handle_ipv4_packet(&self, packet: &[u8], timestamp: u64, decapsulate: bool) -> Option<Sample> {
+        let header = Ipv4Packet::new(packet);
         if let Some(header) = header {
-            self.handle_transport_protocol(IpAddr::V4(header.get_source()),
-                                           IpAddr::V4(header.get_destination()),
-                                           header.get_next_level_protocol(),
-                                           header.payload())
+            let more_fragments = header.get_flags() & Ipv4Flags::MoreFragments != 0;
+            let fragment = if more_fragments || header.get_fragment_offset() != 0 {
+                Some(Fragment {
+                    id: header.get_identification() as u32,
+                    offset: header.get_fragment_offset()
+                })
+            } else {
+                None
+            };
+            // Fragmented tunnels are recorded by their outer headers.
+            if decapsulate && fragment.is_none() {
+                if let Some((tunnel, id, inner, packet)) = tunnel::decapsulate(header.get_next_level_protocol(), header.payload()) {
+                    return self.handle_tunnel(tunnel, id, inner, packet, timestamp);
+                }
+            }
+            self.handle_ip_payload(IpAddr::V4(header.get_source()),
+                                   IpAddr::V4(header.get_destination()),
+                                   header.get_next_level_protocol(),
+                                   header.payload(),
+                                   fragment,
+                                   vec![],
+                                   timestamp)
+                .map(|key| {
+                    let mut sample = Sample::new(key,
+                                                 IpAddr::V4(header.get_source()),
+                                                 header.get_ttl(),
+                                                 header.get_total_length() as u32,
+                                                 timestamp);
+                    sample.fragment = fragment.is_some();
+                    sample
+                })
         } else {
+            self.counters.parse_failed();
             None
         }
     }
 
-    fn handle_ipv6_packet(&self, ethernet: &EthernetPacket) -> Option<SimpleIpfix> {
-        let header = Ipv6Packet::new(ethernet.payload());
+    fn handle_ipv6_packet(&self, packet: &[u8], timestamp: u64, decapsulate: bool) -> Option<Sample> {
+        let header = Ipv6Packet::new(packet);
         if let Some(header) = header {
-            self.handle_transport_protocol(IpAddr::V6(header.get_source()),
-                                           IpAddr::V6(header.get_destination()),
-                                           header.get_next_header(),
-                                           header.payload())
+            let (protocol, payload, extensions, fragment) = match walk_extension_headers(header.get_next_header(), header.payload()) {
+                Some(walked) => walked,
+                None => {
+                    self.counters.parse_failed();
+                    return None;
+                }
+            };
+            if decapsulate && fragment.is_none() {
+                if let Some((tunnel, id, inner, packet)) = tunnel::decapsulate(protocol, payload) {
+                    return self.handle_tunnel(tunnel, id, inner, packet, timestamp);
+                }
+            }
+            let attributes = if extensions.is_empty() {
+                vec![]
+            } else {
+                vec![Attribute::Ipv6Extensions(extensions)]
+            };
+            self.handle_ip_payload(IpAddr::V6(header.get_source()),
+                                   IpAddr::V6(header.get_destination()),
+                                   protocol,
+                                   payload,
+                                   fragment,
+                                   attributes,
+                                   timestamp)
+                .map(|key| {
+                    let mut sample = Sample::new(key,
+                                                 IpAddr::V6(header.get_source()),
+                                                 header.get_hop_limit(),
+                                                 IPV6_HEADER_LENGTH + header.get_payload_length() as u32,
+                                                 timestamp);
+                    sample.fragment = fragment.is_some();
+                    sample
+                })
         } else {
+            self.counters.parse_failed();
             None
         }
     }
 }
 
-fn parse_flags(flags: u16) -> String {
-    lazy_static! {
-        static ref FLAGS: Vec<(u16, &'static str)> = vec![
-            (TcpFlags::SYN, "SYN"),
-            (TcpFlags::FIN, "FIN"),
-            (TcpFlags::ACK, "ACK"),
-            (TcpFlags::RST, "RST"),
-            (TcpFlags::PSH, "PSH"),
-            (TcpFlags::URG, "URG")
-        ];
-    }
-    let flag_names: Vec<String> = FLAGS.iter()
-        .filter(|ref pair|{ has_flag(flags, pair.0) })
-        .map(|ref pair| { pair.1.to_string() }).collect();
-    flag_names.join(",")
-}
-
-fn has_flag(flags: u16, flag: u16) -> bool {
-    (flags & flag) == flag
-}
-
-
-fn create_address(address: IpAddr, port: u16) -> String {
-    format!("{}:{}", address, port)
+/// Resolved settings of a single capturing interface.
+pub struct Capture {
+    pub interface: String,
+    pub processors: u8,
+    /// Sampled frames are also written to rotating pcap files named after the interface.
+    pub dump: Option<Rotation>,
+    /// tcpdump expression, frames it does not match are dropped in the kernel.
+    pub filter: Option<String>,
 }
 
 
-pub fn run_probe(sender: Sender<SimpleIpfix>,
-                 iface_names: Vec<String>,
+pub fn run_probe(sender: Sender<Sample>,
+                 captures: Vec<Capture>,
                  sampling: u32,
-                 processors: u8) -> Vec<thread::JoinHandle<()>> {
+                 rules: Arc<Rules>,
+                 decapsulate: bool) -> Result<Vec<thread::JoinHandle<()>>, String> {
     let mut guards = vec![];
-    for iface_name in iface_names {
-        let (tx, rx) = spmc::channel::<Vec<u8>>();
+    for capture in captures {
+        let Capture { interface, processors, dump, filter } = capture;
+        let filter = match filter {
+            Some(expression) => Some(BpfProgram::compile(expression.as_str())?),
+            None => None
+        };
+        let (tx, rx) = spmc::channel::<(u64, Vec<u8>)>();
         let snd = sender.clone();
-        run_pcap_processor(snd, rx, processors);
+        let counters = interface_counters(interface.as_str());
+        run_pcap_processor(snd, rx, processors, counters.clone(), rules.clone(), decapsulate);
+        let dump_tx = match dump {
+            Some(rotation) => {
+                let writer = RotatingPcapWriter::new(rotation, interface.as_str())?;
+                let (dump_tx, dump_rx) = sync_channel::<(u64, Vec<u8>, usize)>(DUMP_QUEUE_LENGTH);
+                run_pcap_writer(dump_rx, writer);
+                Some(dump_tx)
+            },
+            None => None
+        };
         guards.push(thread::spawn(move || {
-            run_sniffer(iface_name.as_str(), sampling, tx);
+            run_sniffer(interface.as_str(), sampling, tx, dump_tx, counters, filter);
         }));
     }
-    guards
+    Ok(guards)
+}
+
+
+/// Feeds frames of a pcap or pcapng file through the same pipeline as a live interface.
+/// A single processor keeps samples in capture order, windows are cut by their timestamps.
+pub fn run_file_probe(sender: Sender<Sample>,
+                      path: String,
+                      sampling: u32,
+                      filter: Option<String>,
+                      rules: Arc<Rules>,
+                      decapsulate: bool) -> Result<Vec<thread::JoinHandle<()>>, String> {
+    let filter = match filter {
+        Some(expression) => Some(BpfProgram::compile(expression.as_str())?),
+        None => None
+    };
+    let reader = PcapReader::open(path.as_str())?;
+    let (tx, rx) = spmc::channel::<(u64, Vec<u8>)>();
+    let counters = interface_counters(path.as_str());
+    let mut guards = run_pcap_processor(sender, rx, 1, counters.clone(), rules, decapsulate);
+    guards.push(thread::spawn(move || {
+        run_file_reader(reader, sampling, tx, counters, filter);
+    }));
+    Ok(guards)
 }
 
 
-pub fn run_pcap_processor(sender: Sender<SimpleIpfix>,
-                          receiver: spmc::Receiver<Vec<u8>>,
-                          processors: u8) {
+pub fn run_pcap_processor(sender: Sender<Sample>,
+                          receiver: spmc::Receiver<(u64, Vec<u8>)>,
+                          processors: u8,
+                          counters: Arc<InterfaceCounters>,
+                          rules: Arc<Rules>,
+                          decapsulate: bool) -> Vec<thread::JoinHandle<()>> {
+    let mut guards = vec![];
+    // Fragments of a datagram may be handled by different processors.
+    let fragments = Arc::new(Mutex::new(FragmentTable::default()));
     for _ in 0 .. processors {
         let proc_snd = sender.clone();
         let proc_rcv = receiver.clone();
+        let proc_counters = counters.clone();
+        let proc_rules = rules.clone();
+        let proc_fragments = fragments.clone();
 
-        thread::spawn(move || {
-            let probe = Probe::new(proc_snd);
+        guards.push(thread::spawn(move || {
+            let probe = Probe::new(proc_snd, proc_counters, proc_rules, decapsulate, proc_fragments);
             loop {
                 match proc_rcv.recv() {
-                    Ok(pkt) => probe.handle_packet(&EthernetPacket::new(&pkt).unwrap()),
+                    Ok((timestamp, pkt)) => {
+                        probe.counters.dequeued();
+                        if let Some(ethernet) = EthernetPacket::new(&pkt) {
+                            probe.handle_packet(&ethernet, timestamp);
+                        } else {
+                            probe.counters.parse_failed();
+                        }
+                    },
                     Err(e) => { 
                         error!("packetprocessor: queue error occured: {}", e);
                         break;
                     }
                 }
             }
-        });
+        }));
     }
+    guards
 }
 
 
-pub fn run_sniffer(iface_name: &str,
-                   sampling: u32,
-                   sender: spmc::Sender<Vec<u8>>) {
-    let interfaces = datalink::interfaces();
-    let interface = interfaces.into_iter()
-        .filter(|iface| iface.name == iface_name)
-        .next().unwrap();
-
-    let (_, mut rx) = match datalink::channel(&interface, Default::default()) {
-        Ok(Ethernet(tx, rx)) => (tx, rx),
-        Ok(_) => panic!("packetdump: unhandled channel type: {}"),
-        Err(e) => panic!("packetdump: unable to create channel: {}", e),
-    };
-
-    if sampling >= 2u32 {
-        let mut sample_counter = 0u32;
-        loop {
-            match rx.next() {
-                Ok(packet) => {
-                    if sample_counter == 0u32 {
-                        let pkt_vec: Vec<u8> = Vec::from(packet);
-                        match sender.send(pkt_vec) {
-                            Err(e) => {
-                                error!("Error occured during send: {}", e);
-                                break;
-                            },
-                            _ => {}
-                        }
+pub fn run_file_reader(mut reader: PcapReader,
+                       sampling: u32,
+                       sender: spmc::Sender<(u64, Vec<u8>)>,
+                       counters: Arc<InterfaceCounters>,
+                       filter: Option<BpfProgram>) {
+    let mut sample_counter = 0u32;
+    loop {
+        match reader.next_packet() {
+            Ok(Some(packet)) => {
+                if let Some(ref filter) = filter {
+                    if !filter.matches(&packet.1) {
+                        continue;
                     }
-                    sample_counter = (sample_counter + 1) % sampling;
                 }
-                Err(e) => {
-                    error!("packetsniffer: unable to receive packet: {}", e);
-                    break;
+                counters.received();
+                if sample_counter == 0u32 {
+                    counters.enqueued();
+                    if let Err(e) = sender.send(packet) {
+                        counters.dequeued();
+                        counters.send_failed();
+                        error!("Error occured during send: {}", e);
+                        break;
+                    }
+                } else {
+                    counters.sampled_out();
+                }
+                if sampling >= 2u32 {
+                    sample_counter = (sample_counter + 1) % sampling;
                 }
+            },
+            Ok(None) => {
+                info!("Finished reading capture file");
+                break;
+            },
+            Err(e) => {
+                error!("filereader: {}", e);
+                break;
             }
         }
-    } else {
-        loop {
-            match rx.next() {
-                Ok(packet) => {
-                    match sender.send(Vec::from(packet)) {
+    }
+}
+
+
+/// Frames are dropped rather than stalling the sniffer when the writer falls behind.
+fn dump_frame(dump: &Option<SyncSender<(u64, Vec<u8>, usize)>>, frame: &(u64, Vec<u8>), length: usize, counters: &InterfaceCounters) {
+    if let Some(ref dump) = *dump {
+        match dump.try_send((frame.0, frame.1.clone(), length)) {
+            Ok(_) => {},
+            Err(TrySendError::Full(_)) => counters.dump_dropped(),
+            Err(e) => error!("Failed to dump frame, due to: {}", e)
+        }
+    }
+}
+
+
+/// Kernel statistics of the capture socket are read by the collector at every window boundary.
+pub fn run_sniffer(iface_name: &str,
+                   sampling: u32,
+                   sender: spmc::Sender<(u64, Vec<u8>)>,
+                   dump: Option<SyncSender<(u64, Vec<u8>, usize)>>,
+                   counters: Arc<InterfaceCounters>,
+                   filter: Option<BpfProgram>) {
+    let mut rx = match FrameReceiver::open(iface_name, filter.as_ref()) {
+        Ok(rx) => rx,
+        Err(e) => panic!("packetdump: unable to create channel: {}", e),
+    };
+
+    counters.set_statistics_reader(Some(rx.statistics_reader()));
+
+    let mut sample_counter = 0u32;
+    loop {
+        match rx.next() {
+            Ok(Some((packet, length))) => {
+                counters.received();
+                if sample_counter == 0u32 {
+                    let frame = (time_now(), Vec::from(packet));
+                    dump_frame(&dump, &frame, length, &counters);
+                    counters.enqueued();
+                    match sender.send(frame) {
                         Err(e) => {
+                            counters.dequeued();
+                            counters.send_failed();
                             error!("Error occured during send: {}", e);
                             break;
                         },
                         _ => {}
                     }
-                },
-                Err(e) => {
-                    error!("packetsniffer: unable to receive packet: {}", e);
-                    break;
+                } else {
+                    counters.sampled_out();
                 }
+                if sampling >= 2u32 {
+                    sample_counter = (sample_counter + 1) % sampling;
+                }
+            },
+            Ok(None) => {},
+            Err(e) => {
+                error!("packetsniffer: unable to receive packet: {}", e);
+                break;
             }
         }
     }
+    counters.set_statistics_reader(None);
     drop(rx);
 }
-
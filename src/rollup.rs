@@ -0,0 +1,93 @@
+use std::sync::mpsc::{Receiver, Sender};
+use std::thread;
+
+use collector::{window_boundary, Window};
+
+
+/// Merges consecutive windows into aggregates of `length` milliseconds,
+/// aligned to multiples of it the same way collector windows are.
+pub struct Rollup {
+    length: u64,
+    window: Option<Window>,
+}
+
+impl Rollup {
+    pub fn new(length: u64) -> Rollup {
+        Rollup {
+            length: length,
+            window: None
+        }
+    }
+
+    /// Returns aggregates finished by `window`, either because it starts the
+    /// next interval or because it completes the current one.
+    pub fn add(&mut self, window: Window) -> Vec<Window> {
+        let mut finished = vec![];
+        let interval = window_boundary(window.start_time(), self.length);
+        self.window = match self.window.take() {
+            Some(mut current) => {
+                if window_boundary(current.start_time(), self.length) == interval {
+                    if !current.overlaps(&window) && !current.adjoins(&window) {
+                        warn!("Rolling up windows with a gap, {} to {}", current.end_time(), window.start_time());
+                    }
+                    current.merge(window);
+                    Some(current)
+                } else {
+                    finished.push(current);
+                    Some(window)
+                }
+            },
+            None => Some(window)
+        };
+        let complete = match self.window {
+            Some(ref current) => current.end_time() >= interval + self.length,
+            None => false
+        };
+        if complete {
+            finished.extend(self.window.take());
+        }
+        finished
+    }
+
+    pub fn flush(&mut self) -> Option<Window> {
+        self.window.take()
+    }
+}
+
+
+/// Names a rollup length like `30s`, `5m` or `1h`.
+pub fn length_name(seconds: u64) -> String {
+    if seconds % 3600 == 0 {
+        format!("{}h", seconds / 3600)
+    } else if seconds % 60 == 0 {
+        format!("{}m", seconds / 60)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+
+pub fn run_rollup(receiver: Receiver<Window>, sender: Sender<Window>, length: u64) -> thread::JoinHandle<()> {
+
+    thread::spawn(move || {
+        let mut rollup = Rollup::new(length);
+        loop {
+            match receiver.recv() {
+                Ok(window) => {
+                    for aggregate in rollup.add(window) {
+                        sender.send(aggregate).unwrap();
+                    }
+                },
+                Err(e) => {
+                    error!("Rollup receiver error occured: {}", e);
+                    drop(receiver);
+                    if let Some(aggregate) = rollup.flush() {
+                        sender.send(aggregate).unwrap();
+                    }
+                    break;
+                }
+            }
+        }
+    })
+
+}
@@ -0,0 +1,287 @@
+use std::net::IpAddr;
+
+use flow_key::{FlowKey, Protocol};
+
+
+/// Network given as `address/prefix`, a plain address is a single host.
+#[derive(Debug,Clone)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(value: &str) -> Result<Cidr, String> {
+        let mut parts = value.splitn(2, '/');
+        let network: IpAddr = match parts.next().unwrap_or("").parse() {
+            Ok(network) => network,
+            Err(_) => return Err(format!("invalid address in `{}`", value))
+        };
+        let max_prefix = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128
+        };
+        let prefix = match parts.next() {
+            Some(prefix) => match prefix.parse::<u8>() {
+                Ok(prefix) if prefix <= max_prefix => prefix,
+                _ => return Err(format!("invalid prefix length in `{}`", value))
+            },
+            None => max_prefix
+        };
+        Ok(Cidr {
+            network: network,
+            prefix: prefix
+        })
+    }
+
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        match (self.network, *address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => prefix_matches(&network.octets(), &address.octets(), self.prefix),
+            (IpAddr::V6(network), IpAddr::V6(address)) => prefix_matches(&network.octets(), &address.octets(), self.prefix),
+            _ => false
+        }
+    }
+}
+
+
+fn prefix_matches(network: &[u8], address: &[u8], prefix: u8) -> bool {
+    let full_bytes = (prefix / 8) as usize;
+    if network[..full_bytes] != address[..full_bytes] {
+        return false;
+    }
+    let remaining_bits = prefix % 8;
+    if remaining_bits == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - remaining_bits);
+    network[full_bytes] & mask == address[full_bytes] & mask
+}
+
+
+/// Inclusive range of ports, `80` or `1024-65535`.
+#[derive(Debug,Clone)]
+pub struct PortRange {
+    from: u16,
+    to: u16,
+}
+
+impl PortRange {
+    pub fn parse(value: &str) -> Result<PortRange, String> {
+        let mut parts = value.splitn(2, '-');
+        let from = parts.next().unwrap_or("").parse::<u16>();
+        let to = match parts.next() {
+            Some(to) => to.parse::<u16>(),
+            None => from.clone()
+        };
+        match (from, to) {
+            (Ok(from), Ok(to)) if from <= to => Ok(PortRange {
+                from: from,
+                to: to
+            }),
+            _ => Err(format!("invalid port range `{}`", value))
+        }
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        self.from <= port && port <= self.to
+    }
+}
+
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum Action {
+    Allow,
+    Deny,
+    Tag(String),
+}
+
+
+/// `<action> [key=value ...]` where the action is `allow`, `deny` or `tag:<label>`
+/// and keys are `src`, `dst`, `src_port`, `dst_port` and `proto`. All given
+/// conditions have to match, port conditions never match packets without ports.
+#[derive(Debug,Clone)]
+pub struct Rule {
+    action: Action,
+    source: Option<Cidr>,
+    destination: Option<Cidr>,
+    source_port: Option<PortRange>,
+    destination_port: Option<PortRange>,
+    protocol: Option<Protocol>,
+}
+
+impl Rule {
+    pub fn parse(rule: &str) -> Result<Rule, String> {
+        let mut words = rule.split_whitespace();
+        let action = match words.next() {
+            Some("allow") => Action::Allow,
+            Some("deny") => Action::Deny,
+            Some(action) if action.starts_with("tag:") && action.len() > "tag:".len() => {
+                Action::Tag(action["tag:".len()..].to_string())
+            },
+            _ => return Err(format!("rule `{}` must start with `allow`, `deny` or `tag:<label>`", rule))
+        };
+        let mut parsed = Rule {
+            action: action,
+            source: None,
+            destination: None,
+            source_port: None,
+            destination_port: None,
+            protocol: None
+        };
+        for word in words {
+            let mut condition = word.splitn(2, '=');
+            let key = condition.next().unwrap_or("");
+            let value = condition.next().unwrap_or("");
+            let result = match key {
+                "src" => Cidr::parse(value).map(|cidr| { parsed.source = Some(cidr); }),
+                "dst" => Cidr::parse(value).map(|cidr| { parsed.destination = Some(cidr); }),
+                "src_port" => PortRange::parse(value).map(|range| { parsed.source_port = Some(range); }),
+                "dst_port" => PortRange::parse(value).map(|range| { parsed.destination_port = Some(range); }),
+                "proto" => match Protocol::from_name(value) {
+                    Some(protocol) => {
+                        parsed.protocol = Some(protocol);
+                        Ok(())
+                    },
+                    None => Err(format!("unknown protocol `{}`", value))
+                },
+                _ => Err(format!("unknown condition `{}`", word))
+            };
+            if let Err(e) = result {
+                return Err(format!("rule `{}`: {}", rule, e));
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn matches(&self, key: &FlowKey) -> bool {
+        if let Some(protocol) = self.protocol {
+            if protocol != key.protocol {
+                return false;
+            }
+        }
+        if let Some(ref cidr) = self.source {
+            if !cidr.contains(&key.source) {
+                return false;
+            }
+        }
+        if let Some(ref cidr) = self.destination {
+            if !cidr.contains(&key.destination) {
+                return false;
+            }
+        }
+        if let Some(ref range) = self.source_port {
+            match key.source_port {
+                Some(port) if range.contains(port) => (),
+                _ => return false
+            }
+        }
+        if let Some(ref range) = self.destination_port {
+            match key.destination_port {
+                Some(port) if range.contains(port) => (),
+                _ => return false
+            }
+        }
+        true
+    }
+}
+
+
+/// Rules are evaluated in order. Tag rules collect labels and go on, the first
+/// matching allow or deny rule ends the evaluation. Unmatched packets are allowed.
+#[derive(Debug,Clone,Default)]
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+impl Rules {
+    pub fn parse(rules: &[String]) -> Result<Rules, String> {
+        let mut parsed = vec![];
+        for rule in rules {
+            parsed.push(Rule::parse(rule)?);
+        }
+        Ok(Rules {
+            rules: parsed
+        })
+    }
+
+    /// Labels of the matching tag rules, `None` when the packet is denied.
+    pub fn evaluate(&self, key: &FlowKey) -> Option<Vec<String>> {
+        let mut labels = vec![];
+        for rule in self.rules.iter().filter(|rule| { rule.matches(key) }) {
+            match rule.action {
+                Action::Allow => break,
+                Action::Deny => return None,
+                Action::Tag(ref label) => labels.push(label.clone())
+            }
+        }
+        Some(labels)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::{Cidr, PortRange};
+
+    fn address(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn zero_prefix_contains_its_whole_family() {
+        let cidr = Cidr::parse("0.0.0.0/0").unwrap();
+        assert!(cidr.contains(&address("203.0.113.7")));
+        assert!(cidr.contains(&address("255.255.255.255")));
+        assert!(!cidr.contains(&address("::1")));
+        let cidr = Cidr::parse("::/0").unwrap();
+        assert!(cidr.contains(&address("2001:db8::1")));
+        assert!(!cidr.contains(&address("203.0.113.7")));
+    }
+
+    #[test]
+    fn full_prefix_contains_a_single_host() {
+        let cidr = Cidr::parse("2001:db8::1/128").unwrap();
+        assert!(cidr.contains(&address("2001:db8::1")));
+        assert!(!cidr.contains(&address("2001:db8::2")));
+        let cidr = Cidr::parse("192.0.2.1").unwrap();
+        assert!(cidr.contains(&address("192.0.2.1")));
+        assert!(!cidr.contains(&address("192.0.2.0")));
+    }
+
+    #[test]
+    fn partial_prefix_masks_the_last_byte() {
+        let cidr = Cidr::parse("192.0.2.128/25").unwrap();
+        assert!(cidr.contains(&address("192.0.2.200")));
+        assert!(!cidr.contains(&address("192.0.2.127")));
+    }
+
+    #[test]
+    fn rejects_invalid_prefixes() {
+        assert!(Cidr::parse("192.0.2.0/33").is_err());
+        assert!(Cidr::parse("2001:db8::/129").is_err());
+        assert!(Cidr::parse("192.0.2.0/").is_err());
+        assert!(Cidr::parse("example.com/24").is_err());
+    }
+
+    #[test]
+    fn parses_port_ranges() {
+        let range = PortRange::parse("80").unwrap();
+        assert!(range.contains(80));
+        assert!(!range.contains(81));
+        let range = PortRange::parse("1024-65535").unwrap();
+        assert!(range.contains(1024));
+        assert!(range.contains(65535));
+        assert!(!range.contains(1023));
+    }
+
+    #[test]
+    fn rejects_invalid_port_ranges() {
+        assert!(PortRange::parse("").is_err());
+        assert!(PortRange::parse("http").is_err());
+        assert!(PortRange::parse("65536").is_err());
+        assert!(PortRange::parse("443-80").is_err());
+        assert!(PortRange::parse("80-").is_err());
+    }
+}
@@ -0,0 +1,485 @@
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::mpsc::Receiver;
+use std::thread;
+
+use serde_json::{self, Map, Value};
+
+use collector::{Stats, Window};
+use config::Config;
+use flow_collector::Flow;
+use flow_key::{attribute_values, port_name, Attribute, FlowKey, Protocol};
+use monitor::Counters;
+use store::InfluxSink;
+use ttl::HopChange;
+
+
+/// Consumes finished windows and flows, implementations decide where they go.
+pub trait Sink: Send {
+    fn write_window(&mut self, window: &Window) -> Result<(), String>;
+
+    fn write_flows(&mut self, flows: &[Flow]) -> Result<(), String>;
+
+    /// Pipeline counters, sinks without a place for them ignore them.
+    fn write_counters(&mut self, _counters: &Counters) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Called once nothing more will be written.
+    fn close(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+
+/// Where windows end up, rollups are kept apart from the base windows either
+/// by a measurement suffix or by a retention policy.
+#[derive(Debug,Clone)]
+pub struct Destination {
+    pub suffix: String,
+    pub retention_policy: Option<String>,
+}
+
+impl Destination {
+    pub fn base() -> Destination {
+        Destination {
+            suffix: "".to_string(),
+            retention_policy: None
+        }
+    }
+
+    /// Distinguishes files of sinks without measurements or retention policies.
+    pub fn file_suffix(&self) -> String {
+        match self.retention_policy {
+            Some(ref retention_policy) if self.suffix.is_empty() => format!("_{}", retention_policy),
+            _ => self.suffix.clone()
+        }
+    }
+}
+
+
+pub struct NullSink;
+
+impl Sink for NullSink {
+    fn write_window(&mut self, _window: &Window) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn write_flows(&mut self, _flows: &[Flow]) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+
+/// Writes every record as a JSON object on its own line to stdout.
+pub struct JsonLinesSink {
+    destination: Destination,
+}
+
+impl JsonLinesSink {
+    pub fn new(destination: Destination) -> JsonLinesSink {
+        JsonLinesSink {
+            destination: destination
+        }
+    }
+
+    fn record(&self, measurement: &str) -> Map<String, Value> {
+        let mut record = Map::new();
+        record.insert("measurement".to_string(), Value::from(format!("{}{}", measurement, self.destination.suffix)));
+        if let Some(ref retention_policy) = self.destination.retention_policy {
+            record.insert("retention_policy".to_string(), Value::from(retention_policy.as_str()));
+        }
+        record
+    }
+
+    fn write_records(&self, records: Vec<Map<String, Value>>) -> Result<(), String> {
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        for record in records {
+            let line = match serde_json::to_string(&record) {
+                Ok(line) => line,
+                Err(e) => return Err(format!("Unable to serialize record: {}", e))
+            };
+            if let Err(e) = writeln!(out, "{}", line) {
+                return Err(format!("Unable to write to stdout: {}", e));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Sink for JsonLinesSink {
+    fn write_window(&mut self, window: &Window) -> Result<(), String> {
+        let mut records = vec![];
+        for change in window.events() {
+            let mut record = self.record("ttl_events");
+            record.insert("time".to_string(), Value::from(window.end_time()));
+            record.insert("src_ip".to_string(), Value::from(format!("{}", change.source)));
+            insert_hop_change(&mut record, change);
+            records.push(record);
+        }
+        for (key, stats) in window.iter() {
+            let mut record = self.record("pcap_headers");
+            record.insert("time_from".to_string(), Value::from(window.start_time()));
+            record.insert("time_to".to_string(), Value::from(window.end_time()));
+            insert_signature(&mut record, (key.source, key.source_port), (key.destination, key.destination_port),
+                             key.protocol, &key.attributes);
+            insert_stats(&mut record, stats);
+            records.push(record);
+        }
+        for statistics in window.kernel_statistics() {
+            let mut record = self.record("kernel_statistics");
+            record.insert("time".to_string(), Value::from(window.end_time()));
+            record.insert("interface".to_string(), Value::from(statistics.interface.as_str()));
+            record.insert("received".to_string(), Value::from(statistics.received));
+            record.insert("dropped".to_string(), Value::from(statistics.dropped));
+            records.push(record);
+        }
+        self.write_records(records)
+    }
+
+    fn write_flows(&mut self, flows: &[Flow]) -> Result<(), String> {
+        let records = flows.iter()
+            .map(|flow| {
+                let mut record = self.record("flows");
+                record.insert("start_time".to_string(), Value::from(flow.start_time));
+                record.insert("end_time".to_string(), Value::from(flow.end_time));
+                insert_signature(&mut record, (flow.source, flow.source_port), (flow.destination, flow.destination_port),
+                                 flow.protocol, &flow.attributes);
+                record.insert("packets".to_string(), Value::from(flow.pkt_count));
+                record.insert("bytes".to_string(), Value::from(flow.size));
+                record.insert("reverse_packets".to_string(), Value::from(flow.reverse_pkt_count));
+                record.insert("reverse_bytes".to_string(), Value::from(flow.reverse_size));
+                record.insert("min_ttl".to_string(), Value::from(flow.min_ttl));
+                record.insert("max_ttl".to_string(), Value::from(flow.max_ttl));
+                record.insert("reverse_min_ttl".to_string(), Value::from(flow.reverse_min_ttl));
+                record.insert("reverse_max_ttl".to_string(), Value::from(flow.reverse_max_ttl));
+                record
+            })
+            .collect();
+        self.write_records(records)
+    }
+
+    fn write_counters(&mut self, counters: &Counters) -> Result<(), String> {
+        let mut records: Vec<Map<String, Value>> = counters.interfaces.iter()
+            .map(|interface| {
+                let mut record = self.record("probe_counters");
+                record.insert("time".to_string(), Value::from(counters.timestamp));
+                record.insert("interface".to_string(), Value::from(interface.interface.as_str()));
+                record.insert("received".to_string(), Value::from(interface.received));
+                record.insert("sampled_out".to_string(), Value::from(interface.sampled_out));
+                record.insert("parse_failed".to_string(), Value::from(interface.parse_failed));
+                record.insert("unsupported_ethertype".to_string(), Value::from(interface.unsupported_ethertype));
+                record.insert("send_failed".to_string(), Value::from(interface.send_failed));
+                record.insert("unattributed_fragments".to_string(), Value::from(interface.unattributed_fragments));
+                record.insert("dump_dropped".to_string(), Value::from(interface.dump_dropped));
+                record.insert("queue_depth".to_string(), Value::from(interface.queue_depth));
+                record.insert("kernel_received".to_string(), Value::from(interface.kernel_received));
+                record.insert("kernel_dropped".to_string(), Value::from(interface.kernel_dropped));
+                record
+            })
+            .collect();
+        let mut record = self.record("probe_counters");
+        record.insert("time".to_string(), Value::from(counters.timestamp));
+        record.insert("sample_queue_depth".to_string(), Value::from(counters.sample_queue_depth));
+        records.push(record);
+        self.write_records(records)
+    }
+}
+
+
+/// Ports are `null` for protocols without them.
+fn insert_signature(record: &mut Map<String, Value>,
+                    source: (IpAddr, Option<u16>),
+                    destination: (IpAddr, Option<u16>),
+                    protocol: Protocol,
+                    attributes: &[Attribute]) {
+    record.insert("src_ip".to_string(), Value::from(format!("{}", source.0)));
+    record.insert("src_port".to_string(), source.1.map(Value::from).unwrap_or(Value::Null));
+    record.insert("dst_ip".to_string(), Value::from(format!("{}", destination.0)));
+    record.insert("dst_port".to_string(), destination.1.map(Value::from).unwrap_or(Value::Null));
+    record.insert("proto".to_string(), Value::from(protocol.name()));
+    let mut values = Map::new();
+    for (name, value) in attribute_values(protocol, attributes) {
+        values.insert(name.to_string(), Value::from(value));
+    }
+    record.insert("attributes".to_string(), Value::Object(values));
+}
+
+
+fn insert_stats(record: &mut Map<String, Value>, stats: &Stats) {
+    record.insert("cnt".to_string(), Value::from(stats.count));
+    record.insert("bytes".to_string(), Value::from(stats.bytes));
+    record.insert("min_size".to_string(), Value::from(stats.min_size));
+    record.insert("max_size".to_string(), Value::from(stats.max_size));
+    record.insert("mean_size".to_string(), Value::from(stats.mean_size()));
+    record.insert("min_ttl".to_string(), Value::from(stats.min_ttl));
+    record.insert("max_ttl".to_string(), Value::from(stats.max_ttl));
+    record.insert("last_ttl".to_string(), Value::from(stats.last_ttl));
+    record.insert("fragments".to_string(), Value::from(stats.fragments));
+}
+
+
+fn insert_hop_change(record: &mut Map<String, Value>, change: &HopChange) {
+    record.insert("previous_hops".to_string(), Value::from(change.previous.hops));
+    record.insert("previous_initial_ttl".to_string(), Value::from(change.previous.initial_ttl));
+    record.insert("hops".to_string(), Value::from(change.current.hops));
+    record.insert("initial_ttl".to_string(), Value::from(change.current.initial_ttl));
+}
+
+
+const WINDOW_COLUMNS: &'static str = "time_from,time_to,src_ip,src_port,dst_ip,dst_port,proto,attributes,cnt,bytes,min_size,max_size,mean_size,min_ttl,max_ttl,last_ttl,fragments";
+const FLOW_COLUMNS: &'static str = "start_time,end_time,src_ip,src_port,dst_ip,dst_port,proto,attributes,packets,bytes,reverse_packets,reverse_bytes,min_ttl,max_ttl,reverse_min_ttl,reverse_max_ttl";
+
+
+/// Appends window or flow rows to a CSV file, the header is written to empty files only.
+/// Hop change events and kernel statistics have no columns here and are left out.
+pub struct CsvSink {
+    writer: BufWriter<::std::fs::File>,
+    has_header: bool,
+}
+
+impl CsvSink {
+    pub fn new(path: &str, destination: &Destination) -> Result<CsvSink, String> {
+        let path = suffixed_path(path, &destination.file_suffix());
+        let file = match OpenOptions::new().create(true).append(true).open(&path) {
+            Ok(file) => file,
+            Err(e) => return Err(format!("Unable to open CSV file {}: {}", path, e))
+        };
+        let has_header = match file.metadata() {
+            Ok(metadata) => metadata.len() > 0,
+            Err(e) => return Err(format!("Unable to open CSV file {}: {}", path, e))
+        };
+        Ok(CsvSink {
+            writer: BufWriter::new(file),
+            has_header: has_header
+        })
+    }
+
+    fn write_rows(&mut self, columns: &str, rows: Vec<Vec<String>>) -> Result<(), String> {
+        if !self.has_header {
+            self.write_line(columns)?;
+            self.has_header = true;
+        }
+        for row in rows {
+            let line = row.iter()
+                .map(|field| { csv_field(field) })
+                .collect::<Vec<String>>()
+                .join(",");
+            self.write_line(&line)?;
+        }
+        self.writer.flush().map_err(|e| { format!("Unable to write CSV file: {}", e) })
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<(), String> {
+        writeln!(self.writer, "{}", line).map_err(|e| { format!("Unable to write CSV file: {}", e) })
+    }
+}
+
+impl Sink for CsvSink {
+    fn write_window(&mut self, window: &Window) -> Result<(), String> {
+        let rows = window.iter()
+            .map(|(key, stats)| { window_row(window, key, stats) })
+            .collect();
+        self.write_rows(WINDOW_COLUMNS, rows)
+    }
+
+    fn write_flows(&mut self, flows: &[Flow]) -> Result<(), String> {
+        let rows = flows.iter()
+            .map(|flow| {
+                vec![
+                    flow.start_time.to_string(),
+                    flow.end_time.to_string(),
+                    flow.source.to_string(),
+                    port_name(flow.source_port),
+                    flow.destination.to_string(),
+                    port_name(flow.destination_port),
+                    flow.protocol.to_string(),
+                    attribute_list(flow.protocol, &flow.attributes),
+                    flow.pkt_count.to_string(),
+                    flow.size.to_string(),
+                    flow.reverse_pkt_count.to_string(),
+                    flow.reverse_size.to_string(),
+                    flow.min_ttl.to_string(),
+                    flow.max_ttl.to_string(),
+                    flow.reverse_min_ttl.to_string(),
+                    flow.reverse_max_ttl.to_string(),
+                ]
+            })
+            .collect();
+        self.write_rows(FLOW_COLUMNS, rows)
+    }
+}
+
+
+fn window_row(window: &Window, key: &FlowKey, stats: &Stats) -> Vec<String> {
+    vec![
+        window.start_time().to_string(),
+        window.end_time().to_string(),
+        key.source.to_string(),
+        port_name(key.source_port),
+        key.destination.to_string(),
+        port_name(key.destination_port),
+        key.protocol.to_string(),
+        attribute_list(key.protocol, &key.attributes),
+        stats.count.to_string(),
+        stats.bytes.to_string(),
+        stats.min_size.to_string(),
+        stats.max_size.to_string(),
+        stats.mean_size().to_string(),
+        stats.min_ttl.to_string(),
+        stats.max_ttl.to_string(),
+        stats.last_ttl.to_string(),
+        stats.fragments.to_string(),
+    ]
+}
+
+
+fn attribute_list(protocol: Protocol, attributes: &[Attribute]) -> String {
+    attribute_values(protocol, attributes).iter()
+        .map(|&(name, ref value)| { format!("{}={}", name, value) })
+        .collect::<Vec<String>>()
+        .join(";")
+}
+
+
+fn csv_field(field: &str) -> String {
+    if field.contains(',') || field.contains('"') || field.contains('\n') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+
+/// `windows.csv` with suffix `_5m` becomes `windows_5m.csv`.
+pub fn suffixed_path(path: &str, suffix: &str) -> String {
+    if suffix.is_empty() {
+        return path.to_string();
+    }
+    let path = Path::new(path);
+    let stem = path.file_stem().and_then(|stem| { stem.to_str() }).unwrap_or("");
+    let name = match path.extension().and_then(|extension| { extension.to_str() }) {
+        Some(extension) => format!("{}{}.{}", stem, suffix, extension),
+        None => format!("{}{}", stem, suffix)
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+
+/// Hands every window and flow batch to all of its sinks, a failing sink does not stop the others.
+pub struct FanoutSink {
+    sinks: Vec<Box<dyn Sink>>,
+}
+
+impl FanoutSink {
+    pub fn new(sinks: Vec<Box<dyn Sink>>) -> FanoutSink {
+        FanoutSink {
+            sinks: sinks
+        }
+    }
+}
+
+impl Sink for FanoutSink {
+    fn write_window(&mut self, window: &Window) -> Result<(), String> {
+        let errors: Vec<String> = self.sinks.iter_mut()
+            .filter_map(|sink| { sink.write_window(window).err() })
+            .collect();
+        if errors.is_empty() { Ok(()) } else { Err(errors.join("; ")) }
+    }
+
+    fn write_flows(&mut self, flows: &[Flow]) -> Result<(), String> {
+        let errors: Vec<String> = self.sinks.iter_mut()
+            .filter_map(|sink| { sink.write_flows(flows).err() })
+            .collect();
+        if errors.is_empty() { Ok(()) } else { Err(errors.join("; ")) }
+    }
+
+    fn write_counters(&mut self, counters: &Counters) -> Result<(), String> {
+        let errors: Vec<String> = self.sinks.iter_mut()
+            .filter_map(|sink| { sink.write_counters(counters).err() })
+            .collect();
+        if errors.is_empty() { Ok(()) } else { Err(errors.join("; ")) }
+    }
+
+    fn close(&mut self) -> Result<(), String> {
+        let errors: Vec<String> = self.sinks.iter_mut()
+            .filter_map(|sink| { sink.close().err() })
+            .collect();
+        if errors.is_empty() { Ok(()) } else { Err(errors.join("; ")) }
+    }
+}
+
+
+/// Builds the sinks listed in the configuration, `influx`, `stdout`, `csv:<path>`
+/// or `null`. More than one are wrapped in a `FanoutSink`.
+pub fn create_sink(cfg: &Config, destination: &Destination) -> Result<Box<dyn Sink>, String> {
+    let mut sinks: Vec<Box<dyn Sink>> = vec![];
+    for spec in cfg.sinks.iter() {
+        let sink: Box<dyn Sink> = match spec.as_str() {
+            "influx" => Box::new(InfluxSink::new(cfg, destination.clone())),
+            "stdout" => Box::new(JsonLinesSink::new(destination.clone())),
+            "null" => Box::new(NullSink),
+            _ if spec.starts_with("csv:") => Box::new(CsvSink::new(&spec["csv:".len()..], destination)?),
+            _ => return Err(format!("Unknown sink: {}", spec))
+        };
+        sinks.push(sink);
+    }
+    if sinks.len() == 1 {
+        Ok(sinks.remove(0))
+    } else {
+        Ok(Box::new(FanoutSink::new(sinks)))
+    }
+}
+
+
+pub fn run_sink(receiver: Receiver<Window>, mut sink: Box<dyn Sink>) -> thread::JoinHandle<()> {
+
+    thread::spawn(move || {
+        loop {
+            match receiver.recv() {
+                Ok(window) => {
+                    if let Err(e) = sink.write_window(&window) {
+                        error!("{}", e);
+                    }
+                },
+                Err(e) => {
+                    error!("Sink receiver error occured: {}", e);
+                    drop(receiver);
+                    if let Err(e) = sink.close() {
+                        error!("{}", e);
+                    }
+                    break;
+                }
+            }
+        }
+    })
+
+}
+
+
+pub fn run_flow_sink(receiver: Receiver<Vec<Flow>>, mut sink: Box<dyn Sink>) -> thread::JoinHandle<()> {
+
+    thread::spawn(move || {
+        loop {
+            match receiver.recv() {
+                Ok(flows) => {
+                    if let Err(e) = sink.write_flows(&flows) {
+                        error!("{}", e);
+                    }
+                },
+                Err(e) => {
+                    error!("Flow sink receiver error occured: {}", e);
+                    drop(receiver);
+                    if let Err(e) = sink.close() {
+                        error!("{}", e);
+                    }
+                    break;
+                }
+            }
+        }
+    })
+
+}
@@ -0,0 +1,274 @@
+use std::io;
+
+use bpf::BpfProgram;
+
+#[cfg(target_os = "linux")]
+use std::cmp;
+#[cfg(target_os = "linux")]
+use std::ffi::CString;
+#[cfg(target_os = "linux")]
+use std::mem;
+#[cfg(target_os = "linux")]
+use std::sync::Arc;
+#[cfg(target_os = "linux")]
+use libc::{self, c_int, c_uint, c_void, socklen_t};
+#[cfg(target_os = "linux")]
+use bpf::BpfInstruction;
+
+#[cfg(not(target_os = "linux"))]
+use std::time::Duration;
+#[cfg(not(target_os = "linux"))]
+use pnet::datalink::{self, Channel, DataLinkReceiver};
+
+
+#[cfg(target_os = "linux")]
+const ETH_P_ALL: u16 = 0x0003;
+#[cfg(target_os = "linux")]
+const SOL_PACKET: c_int = 263;
+#[cfg(target_os = "linux")]
+const PACKET_ADD_MEMBERSHIP: c_int = 1;
+#[cfg(target_os = "linux")]
+const PACKET_STATISTICS: c_int = 6;
+#[cfg(target_os = "linux")]
+const PACKET_MR_PROMISC: u16 = 1;
+#[cfg(target_os = "linux")]
+const SO_ATTACH_FILTER: c_int = 26;
+
+#[cfg(target_os = "linux")]
+const MAX_FRAME_SIZE: usize = 65536;
+
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct TpacketStats {
+    tp_packets: c_uint,
+    tp_drops: c_uint,
+}
+
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct SockFprog {
+    len: u16,
+    filter: *const BpfInstruction,
+}
+
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct PacketMreq {
+    mr_ifindex: c_int,
+    mr_type: u16,
+    mr_alen: u16,
+    mr_address: [u8; 8],
+}
+
+
+/// Closed once neither the receiver nor a statistics reader uses it.
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+struct Socket {
+    fd: c_int,
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Socket {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+
+/// Promiscuous AF_PACKET socket bound to a single interface. Unlike the pnet
+/// channel it gives access to the kernel statistics of the socket and runs
+/// BPF filters in the kernel.
+#[cfg(target_os = "linux")]
+pub struct FrameReceiver {
+    socket: Arc<Socket>,
+    buffer: Vec<u8>,
+}
+
+#[cfg(target_os = "linux")]
+impl FrameReceiver {
+    pub fn open(interface: &str, filter: Option<&BpfProgram>) -> Result<FrameReceiver, String> {
+        let name = CString::new(interface).map_err(|e| { format!("Invalid interface name {}: {}", interface, e) })?;
+        let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if index == 0 {
+            return Err(format!("Unknown interface {}", interface));
+        }
+        // Protocol 0 receives nothing until `bind` sets the protocol, so no frame
+        // is queued before the filter is attached or from other interfaces.
+        let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW, 0) };
+        if fd < 0 {
+            return Err(format!("Unable to open packet socket on {}: {}", interface, io::Error::last_os_error()));
+        }
+        let receiver = FrameReceiver {
+            socket: Arc::new(Socket {
+                fd: fd
+            }),
+            buffer: vec![0u8; MAX_FRAME_SIZE]
+        };
+
+        if let Some(filter) = filter {
+            let instructions = filter.instructions();
+            let program = SockFprog {
+                len: instructions.len() as u16,
+                filter: instructions.as_ptr()
+            };
+            receiver.set_option(libc::SOL_SOCKET, SO_ATTACH_FILTER, &program)
+                .map_err(|e| { format!("Unable to attach filter `{}` on {}: {}", filter.expression(), interface, e) })?;
+        }
+
+        let mut address: libc::sockaddr_ll = unsafe { mem::zeroed() };
+        address.sll_family = libc::AF_PACKET as u16;
+        address.sll_protocol = ETH_P_ALL.to_be();
+        address.sll_ifindex = index as c_int;
+        let bound = unsafe {
+            libc::bind(fd,
+                       &address as *const libc::sockaddr_ll as *const libc::sockaddr,
+                       mem::size_of::<libc::sockaddr_ll>() as socklen_t)
+        };
+        if bound < 0 {
+            return Err(format!("Unable to bind packet socket to {}: {}", interface, io::Error::last_os_error()));
+        }
+
+        let membership = PacketMreq {
+            mr_ifindex: index as c_int,
+            mr_type: PACKET_MR_PROMISC,
+            mr_alen: 0,
+            mr_address: [0u8; 8]
+        };
+        receiver.set_option(SOL_PACKET, PACKET_ADD_MEMBERSHIP, &membership)
+            .map_err(|e| { format!("Unable to enable promiscuous mode on {}: {}", interface, e) })?;
+        // Keeps the sniffer from blocking forever on quiet interfaces.
+        let timeout = libc::timeval {
+            tv_sec: 1,
+            tv_usec: 0
+        };
+        receiver.set_option(libc::SOL_SOCKET, libc::SO_RCVTIMEO, &timeout)
+            .map_err(|e| { format!("Unable to set receive timeout on {}: {}", interface, e) })?;
+        Ok(receiver)
+    }
+
+    fn set_option<T>(&self, level: c_int, name: c_int, value: &T) -> io::Result<()> {
+        let result = unsafe {
+            libc::setsockopt(self.socket.fd, level, name, value as *const T as *const c_void, mem::size_of::<T>() as socklen_t)
+        };
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Next frame and its length on the wire, `None` when nothing arrived within
+    /// a second. Frames longer than the buffer are cut.
+    pub fn next(&mut self) -> Result<Option<(&[u8], usize)>, String> {
+        // With MSG_TRUNC the length on the wire is returned even if the frame was cut.
+        let length = unsafe {
+            libc::recv(self.socket.fd, self.buffer.as_mut_ptr() as *mut c_void, self.buffer.len(), libc::MSG_TRUNC)
+        };
+        if length < 0 {
+            let error = io::Error::last_os_error();
+            return match error.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted => Ok(None),
+                _ => Err(format!("Unable to receive frame: {}", error))
+            };
+        }
+        let length = length as usize;
+        Ok(Some((&self.buffer[..cmp::min(length, self.buffer.len())], length)))
+    }
+
+    pub fn statistics_reader(&self) -> StatisticsReader {
+        StatisticsReader {
+            socket: self.socket.clone()
+        }
+    }
+}
+
+
+/// Reads the kernel statistics of a capture socket while another thread receives from it.
+#[cfg(target_os = "linux")]
+#[derive(Debug,Clone)]
+pub struct StatisticsReader {
+    socket: Arc<Socket>,
+}
+
+#[cfg(target_os = "linux")]
+impl StatisticsReader {
+    /// Frames seen and frames dropped by the kernel since the last call,
+    /// reading the statistics resets them.
+    pub fn read(&self) -> Result<Option<(u64, u64)>, String> {
+        let mut stats = TpacketStats {
+            tp_packets: 0,
+            tp_drops: 0
+        };
+        let mut length = mem::size_of::<TpacketStats>() as socklen_t;
+        let result = unsafe {
+            libc::getsockopt(self.socket.fd, SOL_PACKET, PACKET_STATISTICS,
+                             &mut stats as *mut TpacketStats as *mut c_void, &mut length)
+        };
+        if result < 0 {
+            return Err(format!("Unable to read packet statistics: {}", io::Error::last_os_error()));
+        }
+        Ok(Some((stats.tp_packets as u64, stats.tp_drops as u64)))
+    }
+}
+
+
+
+/// Falls back to the pnet channel, kernel statistics and filters are not available.
+#[cfg(not(target_os = "linux"))]
+pub struct FrameReceiver {
+    receiver: Box<DataLinkReceiver>,
+}
+
+#[cfg(not(target_os = "linux"))]
+impl FrameReceiver {
+    pub fn open(interface: &str, filter: Option<&BpfProgram>) -> Result<FrameReceiver, String> {
+        if filter.is_some() {
+            return Err(format!("Capture filters on {} are only supported on Linux", interface));
+        }
+        let interface = match datalink::interfaces().into_iter().find(|iface| { iface.name == interface }) {
+            Some(interface) => interface,
+            None => return Err(format!("Unknown interface {}", interface))
+        };
+        let config = datalink::Config {
+            read_timeout: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        match datalink::channel(&interface, config) {
+            Ok(Channel::Ethernet(_, rx)) => Ok(FrameReceiver {
+                receiver: rx
+            }),
+            Ok(_) => Err(format!("Unhandled channel type on {}", interface.name)),
+            Err(e) => Err(format!("Unable to create channel on {}: {}", interface.name, e))
+        }
+    }
+
+    pub fn next(&mut self) -> Result<Option<(&[u8], usize)>, String> {
+        match self.receiver.next() {
+            Ok(frame) => Ok(Some((frame, frame.len()))),
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut || e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(format!("Unable to receive frame: {}", e))
+        }
+    }
+
+    pub fn statistics_reader(&self) -> StatisticsReader {
+        StatisticsReader
+    }
+}
+
+
+#[cfg(not(target_os = "linux"))]
+#[derive(Debug,Clone)]
+pub struct StatisticsReader;
+
+#[cfg(not(target_os = "linux"))]
+impl StatisticsReader {
+    pub fn read(&self) -> Result<Option<(u64, u64)>, String> {
+        Ok(None)
+    }
+}
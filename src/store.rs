@@ -1,76 +1,454 @@
-use std::sync::mpsc::Receiver;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
 use std::thread;
-use influx_db_client::{Client, Point, Points, Value, Precision};
+use std::time::Duration;
+use influx_db_client::{Client, Error, Point, Points, Value, Precision};
+use serde_json;
 
-use collector::{SimpleIpfix, Window};
-use ::CONFIG;
+use collector::{Stats, Window};
+use config::Config;
+use flow_collector::Flow;
+use flow_key::{attribute_values, port_name, FlowKey};
+use monitor::Counters;
+use sink::{suffixed_path, Destination, Sink};
+use ttl::HopChange;
 
-lazy_static! {
-    static ref INFLUX: Client = Client::new(CONFIG.influx_host.as_str(), CONFIG.influx_db.as_str());
+
+/// Milliseconds the doubling retry delay is capped at.
+const MAX_RETRY_DELAY: u64 = 60000;
+
+
+/// A point waiting to be written, kept apart from `Point` so that it can be
+/// spilled to the journal.
+#[derive(Debug,Clone,Serialize,Deserialize)]
+struct PendingPoint {
+    measurement: String,
+    tags: Vec<(String, String)>,
+    fields: Vec<(String, i64)>,
+    timestamp: i64,
+}
+
+impl PendingPoint {
+    fn new(measurement: String, timestamp: u64) -> PendingPoint {
+        PendingPoint {
+            measurement: measurement,
+            tags: vec![],
+            fields: vec![],
+            timestamp: timestamp as i64
+        }
+    }
+
+    /// The line protocol has no empty tag values, such tags are left out.
+    fn add_tag(&mut self, name: &str, value: String) -> () {
+        if !value.is_empty() {
+            self.tags.push((name.to_string(), value));
+        }
+    }
+
+    fn add_field(&mut self, name: &str, value: i64) -> () {
+        self.fields.push((name.to_string(), value));
+    }
+
+    /// Timestamps are kept in milliseconds and converted to `precision` on the way out.
+    fn to_point(&self, precision: &Precision) -> Point {
+        let mut point = Point::new(&self.measurement);
+        for &(ref name, ref value) in self.tags.iter() {
+            point.add_tag(name.clone(), Value::String(value.clone()));
+        }
+        for &(ref name, value) in self.fields.iter() {
+            point.add_field(name.clone(), Value::Integer(value));
+        }
+        let timestamp = match *precision {
+            Precision::Seconds => self.timestamp / 1000,
+            Precision::Microseconds => self.timestamp * 1000,
+            Precision::Nanoseconds => self.timestamp * 1000000,
+            _ => self.timestamp
+        };
+        point.add_timestamp(timestamp);
+        point
+    }
+}
+
+
+type Batch = Vec<PendingPoint>;
+
+
+/// Batches that could not be written, one JSON array per line.
+struct Journal {
+    path: String,
 }
 
+impl Journal {
+    fn append(&self, batch: &Batch) -> Result<(), String> {
+        let line = serde_json::to_string(batch)
+            .map_err(|e| { format!("Unable to serialize batch: {}", e) })?;
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)
+            .map_err(|e| { format!("Unable to open journal {}: {}", self.path, e) })?;
+        writeln!(file, "{}", line).map_err(|e| { format!("Unable to write journal {}: {}", self.path, e) })
+    }
 
-pub fn run_storer(receiver: Receiver<Window>) {
+    fn read(&self) -> Result<Vec<Batch>, String> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(format!("Unable to open journal {}: {}", self.path, e))
+        };
+        let mut batches = vec![];
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|e| { format!("Unable to read journal {}: {}", self.path, e) })?;
+            if line.is_empty() {
+                continue;
+            }
+            match serde_json::from_str(&line) {
+                Ok(batch) => batches.push(batch),
+                Err(e) => warn!("Skipping corrupted journal line in {}: {}", self.path, e)
+            }
+        }
+        Ok(batches)
+    }
+
+    /// Replaces the journal with `batches`, the file is removed when there are none.
+    fn rewrite(&self, batches: &[Batch]) -> Result<(), String> {
+        if let Err(e) = fs::remove_file(&self.path) {
+            if e.kind() != ErrorKind::NotFound {
+                return Err(format!("Unable to remove journal {}: {}", self.path, e));
+            }
+        }
+        for batch in batches {
+            self.append(batch)?;
+        }
+        Ok(())
+    }
+}
+
+
+/// Why a batch was not written.
+enum WriteError {
+    /// The server could not be reached or failed, the batch is worth writing again.
+    Unavailable(String),
+    /// The server refused the batch, writing it again fails the same way.
+    Rejected(String),
+}
+
+
+/// Failed writes are retried with a doubling delay of at most a minute and then queued. Batches over
+/// `influx_queue_size` spill to the journal, which is replayed once writes go
+/// through again and on the next start. Batches the server rejects are dropped.
+pub struct InfluxSink {
+    client: Client,
+    destination: Destination,
+    retention_policy: Option<String>,
+    precision: String,
+    batch_size: usize,
+    retries: u32,
+    retry_delay: u64,
+    queue: VecDeque<Batch>,
+    queue_size: usize,
+    journal: Option<Journal>,
+    failing: bool,
+}
+
+impl InfluxSink {
+    pub fn new(cfg: &Config, destination: Destination) -> InfluxSink {
+        let journal = if cfg.influx_journal.is_empty() {
+            None
+        } else {
+            Some(Journal {
+                path: suffixed_path(&cfg.influx_journal, &destination.file_suffix())
+            })
+        };
+        // `https://` hosts use the client's default TLS connector, there is no
+        // setting for a custom CA, a client certificate or skipping verification.
+        let mut client = Client::new(cfg.influx_host.as_str(), cfg.influx_db.as_str());
+        if !cfg.influx_username.is_empty() {
+            client = client.set_authentication(cfg.influx_username.as_str(), cfg.influx_password.as_str());
+        }
+        // Rollups kept in their own retention policies ignore the configured one.
+        let retention_policy = match destination.retention_policy {
+            Some(ref retention_policy) => Some(retention_policy.clone()),
+            None if !cfg.influx_retention_policy.is_empty() => Some(cfg.influx_retention_policy.clone()),
+            None => None
+        };
+        InfluxSink {
+            client: client,
+            destination: destination,
+            retention_policy: retention_policy,
+            precision: cfg.influx_precision.clone(),
+            batch_size: cfg.influx_batch_size,
+            retries: cfg.influx_retries,
+            retry_delay: cfg.influx_retry_delay,
+            queue: VecDeque::new(),
+            queue_size: cfg.influx_queue_size,
+            journal: journal,
+            failing: false
+        }
+    }
+
+    /// Large batches are written in parts of `batch_size` points. When a later part
+    /// fails the whole batch is written again, Influx overwrites the repeated points.
+    fn write(&self, batch: &Batch) -> Result<(), WriteError> {
+        let retention_policy = self.retention_policy.as_ref().map(|rp| { rp.as_str() });
+        for part in batch.chunks(self.batch_size) {
+            let precision = precision(&self.precision);
+            let points = Points::create_new(part.iter().map(|point| { point.to_point(&precision) }).collect());
+            match self.client.write_points(points, Some(precision), retention_policy) {
+                Ok(()) => {},
+                // Unexpected responses are mostly server errors that go away again.
+                Err(e @ Error::Communication(_)) | Err(e @ Error::Unknow(_)) => {
+                    return Err(WriteError::Unavailable(format!("Unable to write to InfluxDB: {:?}", e)));
+                },
+                Err(e) => return Err(WriteError::Rejected(format!("InfluxDB rejected a batch of {} points: {:?}", batch.len(), e)))
+            }
+        }
+        Ok(())
+    }
 
-    thread::spawn(move || {
+    /// Retries are skipped while the database is known to be down, the queue covers that.
+    fn write_with_retries(&self, batch: &Batch) -> Result<(), WriteError> {
+        let retries = if self.failing { 0 } else { self.retries };
+        let mut delay = self.retry_delay;
+        let mut attempt = 0;
         loop {
-            match receiver.recv() {
-                Ok(window) => {
-                    println!("{} μs", window.end_time());
-                    save_window(window);
+            match self.write(batch) {
+                Ok(()) => return Ok(()),
+                Err(WriteError::Unavailable(e)) => {
+                    if attempt >= retries {
+                        return Err(WriteError::Unavailable(e));
+                    }
+                    warn!("{}, retrying in {} ms", e, delay);
+                    thread::sleep(Duration::from_millis(delay));
+                    delay = cmp::min(delay.saturating_mul(2), MAX_RETRY_DELAY);
+                    attempt += 1;
                 },
-                Err(e) => {
-                    error!("Storer receiver error occured: {}", e);
-                    drop(receiver);
-                    break;
+                Err(e) => return Err(e)
+            }
+        }
+    }
+
+    fn push(&mut self, batch: Batch) -> Result<(), String> {
+        if !batch.is_empty() {
+            self.queue.push_back(batch);
+        }
+        let result = self.flush();
+        let queue_size = self.queue_size;
+        self.spill(queue_size);
+        result
+    }
+
+    /// Writes queued batches oldest first and replays the journal once the queue is empty.
+    fn flush(&mut self) -> Result<(), String> {
+        while let Some(batch) = self.queue.pop_front() {
+            match self.write_with_retries(&batch) {
+                Ok(()) => {},
+                Err(WriteError::Rejected(e)) => error!("{}, the batch is dropped", e),
+                Err(WriteError::Unavailable(e)) => {
+                    self.queue.push_front(batch);
+                    self.failing = true;
+                    return Err(format!("{}, {} batches pending", e, self.queue.len()));
                 }
             }
+            if self.failing {
+                info!("InfluxDB writes go through again");
+                self.failing = false;
+            }
+        }
+        let result = match self.journal {
+            Some(ref journal) => self.replay(journal),
+            None => Ok(())
+        };
+        if result.is_err() {
+            self.failing = true;
+        }
+        result
+    }
+
+    fn replay(&self, journal: &Journal) -> Result<(), String> {
+        let batches = journal.read()?;
+        if batches.is_empty() {
+            return Ok(());
+        }
+        info!("Replaying {} batches from {}", batches.len(), journal.path);
+        for (index, batch) in batches.iter().enumerate() {
+            match self.write(batch) {
+                Ok(()) => {},
+                Err(WriteError::Rejected(e)) => error!("{}, the batch is dropped from {}", e, journal.path),
+                Err(WriteError::Unavailable(e)) => {
+                    journal.rewrite(&batches[index..])?;
+                    return Err(format!("{}, {} batches left in {}", e, batches.len() - index, journal.path));
+                }
+            }
+        }
+        journal.rewrite(&[])
+    }
+
+    /// Moves the oldest batches over `keep` from the queue to the journal,
+    /// without a journal they are dropped.
+    fn spill(&mut self, keep: usize) -> () {
+        let mut dropped = 0;
+        while self.queue.len() > keep {
+            let batch = self.queue.pop_front().unwrap();
+            let spilled = match self.journal {
+                Some(ref journal) => journal.append(&batch),
+                None => Err("no journal is configured".to_string())
+            };
+            if let Err(e) = spilled {
+                error!("{}", e);
+                dropped += 1;
+            }
+        }
+        if dropped > 0 {
+            error!("Dropped {} batches that could not be written to InfluxDB", dropped);
+        }
+    }
+}
+
+impl Sink for InfluxSink {
+    fn write_window(&mut self, window: &Window) -> Result<(), String> {
+        let batch = create_points(window, &self.destination.suffix);
+        self.push(batch)
+    }
+
+    fn write_flows(&mut self, flows: &[Flow]) -> Result<(), String> {
+        let batch = flows.iter()
+            .map(|flow| { create_flow_point(flow) })
+            .collect();
+        self.push(batch)
+    }
+
+    fn write_counters(&mut self, counters: &Counters) -> Result<(), String> {
+        let batch = create_counter_points(counters);
+        self.push(batch)
+    }
+
+    /// Gives pending batches a last chance and spills the rest to the journal.
+    fn close(&mut self) -> Result<(), String> {
+        if self.queue.is_empty() {
+            return Ok(());
         }
-    });
-    
+        let result = self.flush();
+        self.spill(0);
+        result
+    }
 }
 
 
-pub fn save_window(window: Window) {
-    INFLUX.write_points(create_points(window), Some(Precision::Milliseconds), None).unwrap();
+fn precision(name: &str) -> Precision {
+    match name {
+        "s" => Precision::Seconds,
+        "us" => Precision::Microseconds,
+        "ns" => Precision::Nanoseconds,
+        _ => Precision::Milliseconds
+    }
 }
 
 
-fn create_points(window: Window) -> Points {
+fn create_points(window: &Window, suffix: &str) -> Batch {
+    let start_time = window.start_time();
     let timestamp = window.end_time();
-    let points: Vec<Point> = window.into_iter()
-        .map(|(ipfix, cnt)| { create_point(ipfix, cnt, timestamp) })
+    let mut points: Batch = window.events().iter()
+        .map(|change| { create_event_point(change, timestamp, suffix) })
         .collect();
-    Points::create_new(points)
+    points.extend(window.iter()
+        .map(|(key, stats)| { create_point(key, stats, start_time, timestamp, suffix) }));
+    points.extend(window.kernel_statistics().iter()
+        .map(|statistics| {
+            let mut point = PendingPoint::new(format!("kernel_statistics{}", suffix), timestamp);
+            point.add_tag("interface", statistics.interface.clone());
+            point.add_field("received", statistics.received as i64);
+            point.add_field("dropped", statistics.dropped as i64);
+            point
+        }));
+    points
 }
 
 
-fn create_point(ipfix: SimpleIpfix, cnt: u32, timestamp: u64) -> Point {
-    let mut point: Point = point!("pcap_headers");
-    let (src_ip, src_port) = parse_address(ipfix.0); 
-    let (dst_ip, dst_port) = parse_address(ipfix.1);
-    point.add_tag("src_ip", src_ip);
-    point.add_tag("src_port", src_port);
-    point.add_tag("dst_ip", dst_ip);
-    point.add_tag("dst_port", dst_port);
-    point.add_tag("proto", Value::String(ipfix.2.to_string()));
-    point.add_timestamp(timestamp as i64);
-    for (tag_name, tag_value) in ipfix.3 {
-        point.add_tag(tag_name.to_string(), Value::String(tag_value));
-    }
-    point.add_field("cnt", Value::Integer(cnt as i64));
+fn create_event_point(change: &HopChange, timestamp: u64, suffix: &str) -> PendingPoint {
+    let mut point = PendingPoint::new(format!("ttl_events{}", suffix), timestamp);
+    point.add_tag("src_ip", format!("{}", change.source));
+    point.add_field("previous_hops", change.previous.hops as i64);
+    point.add_field("previous_initial_ttl", change.previous.initial_ttl as i64);
+    point.add_field("hops", change.current.hops as i64);
+    point.add_field("initial_ttl", change.current.initial_ttl as i64);
     point
 }
 
 
-fn parse_address(address: String) -> (Value, Value) {
-    let address: Vec<&str> = address.split(':').collect();
-    match address.len() {
-        2 => {
-            (Value::String(address[0].to_string()), Value::String(address[1].to_string()))
-        }
-        _ => {
-            (Value::String(address[0].to_string()), Value::String("-".to_string()))
-        }
+/// Points are stamped with the window end, `start_time` completes the interval.
+fn create_point(key: &FlowKey, stats: &Stats, start_time: u64, timestamp: u64, suffix: &str) -> PendingPoint {
+    let mut point = PendingPoint::new(format!("pcap_headers{}", suffix), timestamp);
+    point.add_tag("src_ip", format!("{}", key.source));
+    point.add_tag("src_port", port_name(key.source_port));
+    point.add_tag("dst_ip", format!("{}", key.destination));
+    point.add_tag("dst_port", port_name(key.destination_port));
+    point.add_tag("proto", key.protocol.to_string());
+    for (tag_name, tag_value) in key.attribute_values() {
+        point.add_tag(tag_name, tag_value);
     }
+    point.add_field("cnt", stats.count as i64);
+    point.add_field("bytes", stats.bytes as i64);
+    point.add_field("min_size", stats.min_size as i64);
+    point.add_field("max_size", stats.max_size as i64);
+    point.add_field("mean_size", stats.mean_size() as i64);
+    point.add_field("min_ttl", stats.min_ttl as i64);
+    point.add_field("max_ttl", stats.max_ttl as i64);
+    point.add_field("last_ttl", stats.last_ttl as i64);
+    point.add_field("fragments", stats.fragments as i64);
+    point.add_field("start_time", start_time as i64);
+    point
 }
+
+
+fn create_counter_points(counters: &Counters) -> Batch {
+    let mut points: Batch = counters.interfaces.iter()
+        .map(|interface| {
+            let mut point = PendingPoint::new("probe_counters".to_string(), counters.timestamp);
+            point.add_tag("interface", interface.interface.clone());
+            point.add_field("received", interface.received as i64);
+            point.add_field("sampled_out", interface.sampled_out as i64);
+            point.add_field("parse_failed", interface.parse_failed as i64);
+            point.add_field("unsupported_ethertype", interface.unsupported_ethertype as i64);
+            point.add_field("send_failed", interface.send_failed as i64);
+            point.add_field("unattributed_fragments", interface.unattributed_fragments as i64);
+            point.add_field("dump_dropped", interface.dump_dropped as i64);
+            point.add_field("queue_depth", interface.queue_depth as i64);
+            point.add_field("kernel_received", interface.kernel_received as i64);
+            point.add_field("kernel_dropped", interface.kernel_dropped as i64);
+            point
+        })
+        .collect();
+    let mut point = PendingPoint::new("probe_counters".to_string(), counters.timestamp);
+    point.add_field("sample_queue_depth", counters.sample_queue_depth as i64);
+    points.push(point);
+    points
+}
+
+
+fn create_flow_point(flow: &Flow) -> PendingPoint {
+    let mut point = PendingPoint::new("flows".to_string(), flow.end_time);
+    point.add_tag("src_ip", format!("{}", flow.source));
+    point.add_tag("src_port", port_name(flow.source_port));
+    point.add_tag("dst_ip", format!("{}", flow.destination));
+    point.add_tag("dst_port", port_name(flow.destination_port));
+    point.add_tag("proto", flow.protocol.to_string());
+    let mut attributes: HashMap<&'static str, Vec<String>> = HashMap::new();
+    for (name, value) in attribute_values(flow.protocol, &flow.attributes) {
+        attributes.entry(name).or_insert_with(Vec::new).push(value);
+    }
+    for (tag_name, tag_values) in attributes {
+        point.add_tag(tag_name, tag_values.join(";"));
+    }
+    point.add_field("packets", flow.pkt_count as i64);
+    point.add_field("bytes", flow.size as i64);
+    point.add_field("reverse_packets", flow.reverse_pkt_count as i64);
+    point.add_field("reverse_bytes", flow.reverse_size as i64);
+    point.add_field("min_ttl", flow.min_ttl as i64);
+    point.add_field("max_ttl", flow.max_ttl as i64);
+    point.add_field("reverse_min_ttl", flow.reverse_min_ttl as i64);
+    point.add_field("reverse_max_ttl", flow.reverse_max_ttl as i64);
+    point.add_field("start_time", flow.start_time as i64);
+    point.add_field("duration", flow.duration() as i64);
+    point
+}
+
@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+
+
+/// Default initial TTLs used by common network stacks (Linux/BSD, Windows, network gear).
+const INITIAL_TTLS: [u8; 3] = [64u8, 128u8, 255u8];
+
+/// Windows a source may go unseen before its hop distance is forgotten.
+const HOP_EXPIRY_WINDOWS: u64 = 60;
+
+
+pub fn initial_ttl(ttl: u8) -> u8 {
+    match INITIAL_TTLS.iter().find(|&&initial| { ttl <= initial }) {
+        Some(initial) => *initial,
+        None => 255u8
+    }
+}
+
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct HopDistance {
+    pub initial_ttl: u8,
+    pub hops: u8,
+}
+
+impl HopDistance {
+    pub fn from_ttl(ttl: u8) -> HopDistance {
+        let initial = initial_ttl(ttl);
+        HopDistance {
+            initial_ttl: initial,
+            hops: initial - ttl
+        }
+    }
+}
+
+impl fmt::Display for HopDistance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} hops (initial ttl {})", self.hops, self.initial_ttl)
+    }
+}
+
+
+#[derive(Debug,Clone)]
+pub struct HopChange {
+    pub source: IpAddr,
+    pub previous: HopDistance,
+    pub current: HopDistance,
+}
+
+
+/// Remembers the last known hop distance of every source and reports sources
+/// whose distance differs from the one seen in an earlier window. Sources not
+/// seen for `HOP_EXPIRY_WINDOWS` windows are forgotten.
+pub struct HopTracker {
+    distances: HashMap<IpAddr, (HopDistance, u64)>,
+    window: u64,
+}
+
+impl HopTracker {
+    pub fn new() -> HopTracker {
+        HopTracker {
+            distances: HashMap::new(),
+            window: 0
+        }
+    }
+
+    pub fn update(&mut self, sources: &HashMap<IpAddr, HopDistance>) -> Vec<HopChange> {
+        let mut changes = vec![];
+        let window = self.window;
+        for (source, current) in sources.iter() {
+            if let Some((previous, _)) = self.distances.insert(*source, (*current, window)) {
+                if previous != *current {
+                    changes.push(HopChange {
+                        source: *source,
+                        previous: previous,
+                        current: *current
+                    });
+                }
+            }
+        }
+        self.distances.retain(|_, &mut (_, seen)| { window - seen < HOP_EXPIRY_WINDOWS });
+        self.window += 1;
+        changes
+    }
+}
+
+impl Default for HopTracker {
+    fn default() -> HopTracker {
+        HopTracker::new()
+    }
+}
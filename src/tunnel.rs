@@ -0,0 +1,231 @@
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+
+
+const VXLAN_PORT: u16 = 4789;
+const GENEVE_PORT: u16 = 6081;
+
+const UDP_HEADER_LENGTH: usize = 8;
+const GRE_HEADER_LENGTH: usize = 4;
+const VXLAN_HEADER_LENGTH: usize = 8;
+const GENEVE_HEADER_LENGTH: usize = 8;
+
+const GRE_CHECKSUM_PRESENT: u16 = 0x8000;
+const GRE_KEY_PRESENT: u16 = 0x2000;
+const GRE_SEQUENCE_PRESENT: u16 = 0x1000;
+const VXLAN_VNI_VALID: u8 = 0x08;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_TRANSPARENT_ETHERNET: u16 = 0x6558;
+
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub enum TunnelType {
+    Gre,
+    Vxlan,
+    Geneve,
+    IpInIp,
+}
+
+impl TunnelType {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            TunnelType::Gre => "GRE",
+            TunnelType::Vxlan => "VXLAN",
+            TunnelType::Geneve => "GENEVE",
+            TunnelType::IpInIp => "IPinIP"
+        }
+    }
+}
+
+
+/// What a tunnel carries.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Inner {
+    Ethernet,
+    Ipv4,
+    Ipv6,
+}
+
+
+/// Tunnel type, VNI or GRE key, inner packet type and the inner packet of `packet`,
+/// the payload of an IP packet carrying `protocol`. `None` when it is no tunnel
+/// or the tunnel carries something else than Ethernet or IP.
+pub fn decapsulate(protocol: IpNextHeaderProtocol, packet: &[u8]) -> Option<(TunnelType, Option<u32>, Inner, &[u8])> {
+    match protocol {
+        IpNextHeaderProtocols::Ipv4 => Some((TunnelType::IpInIp, None, Inner::Ipv4, packet)),
+        IpNextHeaderProtocols::Ipv6 => Some((TunnelType::IpInIp, None, Inner::Ipv6, packet)),
+        IpNextHeaderProtocols::Gre => decapsulate_gre(packet),
+        IpNextHeaderProtocols::Udp if packet.len() >= UDP_HEADER_LENGTH => {
+            let payload = &packet[UDP_HEADER_LENGTH..];
+            match read_u16(&packet[2..]) {
+                VXLAN_PORT => decapsulate_vxlan(payload),
+                GENEVE_PORT => decapsulate_geneve(payload),
+                _ => None
+            }
+        },
+        _ => None
+    }
+}
+
+
+fn decapsulate_gre(packet: &[u8]) -> Option<(TunnelType, Option<u32>, Inner, &[u8])> {
+    if packet.len() < GRE_HEADER_LENGTH {
+        return None;
+    }
+    let flags = read_u16(packet);
+    let inner = inner_type(read_u16(&packet[2..]))?;
+    let mut offset = GRE_HEADER_LENGTH;
+    if flags & GRE_CHECKSUM_PRESENT != 0 {
+        offset += 4;
+    }
+    let key = if flags & GRE_KEY_PRESENT != 0 {
+        if packet.len() < offset + 4 {
+            return None;
+        }
+        offset += 4;
+        Some(read_u32(&packet[offset - 4..]))
+    } else {
+        None
+    };
+    if flags & GRE_SEQUENCE_PRESENT != 0 {
+        offset += 4;
+    }
+    if packet.len() < offset {
+        return None;
+    }
+    Some((TunnelType::Gre, key, inner, &packet[offset..]))
+}
+
+
+fn decapsulate_vxlan(packet: &[u8]) -> Option<(TunnelType, Option<u32>, Inner, &[u8])> {
+    if packet.len() < VXLAN_HEADER_LENGTH {
+        return None;
+    }
+    let vni = if packet[0] & VXLAN_VNI_VALID != 0 {
+        Some(read_u32(&packet[4..]) >> 8)
+    } else {
+        None
+    };
+    Some((TunnelType::Vxlan, vni, Inner::Ethernet, &packet[VXLAN_HEADER_LENGTH..]))
+}
+
+
+fn decapsulate_geneve(packet: &[u8]) -> Option<(TunnelType, Option<u32>, Inner, &[u8])> {
+    if packet.len() < GENEVE_HEADER_LENGTH {
+        return None;
+    }
+    // Options follow the fixed header, their length is given in 4 byte words.
+    let offset = GENEVE_HEADER_LENGTH + (packet[0] & 0x3f) as usize * 4;
+    if packet.len() < offset {
+        return None;
+    }
+    let inner = inner_type(read_u16(&packet[2..]))?;
+    let vni = read_u32(&packet[4..]) >> 8;
+    Some((TunnelType::Geneve, Some(vni), inner, &packet[offset..]))
+}
+
+
+fn inner_type(ethertype: u16) -> Option<Inner> {
+    match ethertype {
+        ETHERTYPE_IPV4 => Some(Inner::Ipv4),
+        ETHERTYPE_IPV6 => Some(Inner::Ipv6),
+        ETHERTYPE_TRANSPARENT_ETHERNET => Some(Inner::Ethernet),
+        _ => None
+    }
+}
+
+
+fn read_u16(buffer: &[u8]) -> u16 {
+    ((buffer[0] as u16) << 8) | buffer[1] as u16
+}
+
+
+fn read_u32(buffer: &[u8]) -> u32 {
+    ((read_u16(buffer) as u32) << 16) | read_u16(&buffer[2..]) as u32
+}
+
+
+#[cfg(test)]
+mod tests {
+    use pnet::packet::ip::IpNextHeaderProtocols;
+
+    use super::{decapsulate, Inner, TunnelType};
+
+    const PAYLOAD: [u8; 4] = [0xde, 0xad, 0xbe, 0xef];
+
+    fn gre(flags: u16, ethertype: u16, fields: &[u8]) -> Vec<u8> {
+        let mut packet = vec![(flags >> 8) as u8, flags as u8, (ethertype >> 8) as u8, ethertype as u8];
+        packet.extend_from_slice(fields);
+        packet.extend_from_slice(&PAYLOAD);
+        packet
+    }
+
+    fn udp(destination_port: u16, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0xc0, 0x00, (destination_port >> 8) as u8, destination_port as u8, 0, 0, 0, 0];
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    #[test]
+    fn reads_gre_without_optional_fields() {
+        let packet = gre(0x0000, 0x0800, &[]);
+        assert_eq!(decapsulate(IpNextHeaderProtocols::Gre, &packet),
+                   Some((TunnelType::Gre, None, Inner::Ipv4, &PAYLOAD[..])));
+    }
+
+    #[test]
+    fn reads_gre_key_after_checksum() {
+        let packet = gre(0xa000, 0x86dd, &[0, 0, 0, 0, 0, 0, 0x01, 0x02]);
+        assert_eq!(decapsulate(IpNextHeaderProtocols::Gre, &packet),
+                   Some((TunnelType::Gre, Some(0x0102), Inner::Ipv6, &PAYLOAD[..])));
+    }
+
+    #[test]
+    fn skips_gre_sequence_number() {
+        let packet = gre(0x3000, 0x6558, &[0, 0, 0, 7, 0, 0, 0, 1]);
+        assert_eq!(decapsulate(IpNextHeaderProtocols::Gre, &packet),
+                   Some((TunnelType::Gre, Some(7), Inner::Ethernet, &PAYLOAD[..])));
+        let packet = gre(0x1000, 0x0800, &[0, 0, 0, 1]);
+        assert_eq!(decapsulate(IpNextHeaderProtocols::Gre, &packet),
+                   Some((TunnelType::Gre, None, Inner::Ipv4, &PAYLOAD[..])));
+    }
+
+    #[test]
+    fn rejects_truncated_gre() {
+        assert_eq!(decapsulate(IpNextHeaderProtocols::Gre, &[0x00, 0x00, 0x08]), None);
+        assert_eq!(decapsulate(IpNextHeaderProtocols::Gre, &[0x20, 0x00, 0x08, 0x00, 0x00, 0x00]), None);
+        assert_eq!(decapsulate(IpNextHeaderProtocols::Gre, &[0x10, 0x00, 0x08, 0x00, 0x00]), None);
+    }
+
+    #[test]
+    fn reads_vxlan_vni() {
+        let mut header = vec![0x08, 0, 0, 0, 0x12, 0x34, 0x56, 0];
+        header.extend_from_slice(&PAYLOAD);
+        assert_eq!(decapsulate(IpNextHeaderProtocols::Udp, &udp(4789, &header)),
+                   Some((TunnelType::Vxlan, Some(0x123456), Inner::Ethernet, &PAYLOAD[..])));
+        assert_eq!(decapsulate(IpNextHeaderProtocols::Udp, &udp(4789, &header[..7])), None);
+    }
+
+    #[test]
+    fn skips_geneve_options() {
+        // Two 4 byte words of options.
+        let mut header = vec![0x02, 0, 0x08, 0x00, 0x00, 0x00, 0x2a, 0, 1, 2, 3, 4, 5, 6, 7, 8];
+        header.extend_from_slice(&PAYLOAD);
+        assert_eq!(decapsulate(IpNextHeaderProtocols::Udp, &udp(6081, &header)),
+                   Some((TunnelType::Geneve, Some(42), Inner::Ipv4, &PAYLOAD[..])));
+    }
+
+    #[test]
+    fn rejects_truncated_geneve() {
+        let header = [0x02, 0, 0x08, 0x00, 0x00, 0x00, 0x2a, 0, 1, 2, 3, 4];
+        assert_eq!(decapsulate(IpNextHeaderProtocols::Udp, &udp(6081, &header)), None);
+        assert_eq!(decapsulate(IpNextHeaderProtocols::Udp, &udp(6081, &header[..7])), None);
+    }
+
+    #[test]
+    fn ignores_other_udp_ports() {
+        assert_eq!(decapsulate(IpNextHeaderProtocols::Udp, &udp(53, &PAYLOAD)), None);
+        assert_eq!(decapsulate(IpNextHeaderProtocols::Udp, &[0, 0, 0x12]), None);
+    }
+}